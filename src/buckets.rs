@@ -0,0 +1,105 @@
+use serde::Serialize;
+
+use crate::numbers::Percentage;
+
+/// Bucket is a coarse size class for a percentage change, so breadth queries
+/// like "how many big movers were there?" are a GROUP BY away
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    /// Down by more than the big threshold
+    BigLoss,
+    /// Down by more than the small threshold, up to the big one
+    Loss,
+    /// Within the small threshold either way
+    Flat,
+    /// Up by more than the small threshold, up to the big one
+    Gain,
+    /// Up by more than the big threshold
+    BigGain,
+}
+
+impl Bucket {
+    /// The label we store in the after_market table's bucket column
+    pub fn label(self) -> &'static str {
+        match self {
+            Bucket::BigLoss => "big_loss",
+            Bucket::Loss => "loss",
+            Bucket::Flat => "flat",
+            Bucket::Gain => "gain",
+            Bucket::BigGain => "big_gain",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Bucket> {
+        match label {
+            "big_loss" => Some(Bucket::BigLoss),
+            "loss" => Some(Bucket::Loss),
+            "flat" => Some(Bucket::Flat),
+            "gain" => Some(Bucket::Gain),
+            "big_gain" => Some(Bucket::BigGain),
+            _ => None,
+        }
+    }
+}
+
+/// The sizes, in percent, that separate the buckets. They apply the same way
+/// to gains and losses, so the defaults give <-5%, -5..-2%, -2..2%, 2..5% and
+/// >5%.
+#[derive(Clone, Copy, Debug)]
+pub struct BucketThresholds {
+    pub small: f64,
+    pub big: f64,
+}
+
+/// Put `percentage` in its bucket. A change of exactly a threshold belongs to
+/// the smaller bucket, so with the defaults 2% is flat and -5% is a loss.
+pub fn percentage_bucket(percentage: Percentage, thresholds: &BucketThresholds) -> Bucket {
+    let p = percentage.value();
+    if p < -thresholds.big {
+        Bucket::BigLoss
+    } else if p < -thresholds.small {
+        Bucket::Loss
+    } else if p <= thresholds.small {
+        Bucket::Flat
+    } else if p <= thresholds.big {
+        Bucket::Gain
+    } else {
+        Bucket::BigGain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(p: f64) -> Bucket {
+        let thresholds = BucketThresholds {
+            small: 2.0,
+            big: 5.0,
+        };
+        percentage_bucket(Percentage::new(p).unwrap(), &thresholds)
+    }
+
+    #[test]
+    fn puts_exact_thresholds_in_the_smaller_bucket() {
+        assert_eq!(bucket(-5.0), Bucket::Loss);
+        assert_eq!(bucket(-2.0), Bucket::Flat);
+        assert_eq!(bucket(2.0), Bucket::Flat);
+        assert_eq!(bucket(5.0), Bucket::Gain);
+    }
+
+    #[test]
+    fn buckets_either_side_of_each_threshold() {
+        assert_eq!(bucket(-5.01), Bucket::BigLoss);
+        assert_eq!(bucket(-4.99), Bucket::Loss);
+        assert_eq!(bucket(-2.01), Bucket::Loss);
+        assert_eq!(bucket(-1.99), Bucket::Flat);
+        assert_eq!(bucket(0.0), Bucket::Flat);
+        assert_eq!(bucket(1.99), Bucket::Flat);
+        assert_eq!(bucket(2.01), Bucket::Gain);
+        assert_eq!(bucket(4.99), Bucket::Gain);
+        assert_eq!(bucket(5.01), Bucket::BigGain);
+        assert_eq!(bucket(-100.0), Bucket::BigLoss);
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc, Weekday};
+use chrono_tz::America::New_York;
+
+/// The US exchanges' trading day that `now` falls on, which is the date in
+/// New York rather than in UTC (so e.g. 8pm Eastern is still that day)
+pub fn trading_date(now: &DateTime<Utc>) -> NaiveDate {
+    now.with_timezone(&New_York).naive_local().date()
+}
+
+/// The name Postgres knows the trading day's time zone by, for working out
+/// trading dates in SQL the same way `trading_date` does
+pub fn trading_time_zone_name() -> &'static str {
+    New_York.name()
+}
+
+/// Whether the US exchanges are open on `date`: any weekday that isn't one of
+/// the given market holidays
+pub fn is_trading_day(date: NaiveDate, holidays: &[NaiveDate]) -> bool {
+    match date.weekday() {
+        Weekday::Sat | Weekday::Sun => false,
+        _ => !holidays.contains(&date),
+    }
+}
+
+/// The first and last instants of the trading day `date`, i.e. New York's
+/// midnight to midnight on that date
+pub fn trading_day_bounds(date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_of = |day: NaiveDate| {
+        New_York
+            .from_local_datetime(&day.and_hms(0, 0, 0))
+            .earliest()
+            .expect("midnight always exists in New York")
+            .with_timezone(&Utc)
+    };
+
+    let start = start_of(date);
+    let end = start_of(date.succ()) - Duration::microseconds(1);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    #[test]
+    fn weekends_and_holidays_arent_trading_days() {
+        let holidays = [day(2020, 7, 3)];
+        assert!(!is_trading_day(day(2020, 7, 4), &holidays)); // a Saturday
+        assert!(!is_trading_day(day(2020, 7, 3), &holidays));
+        assert!(is_trading_day(day(2020, 7, 2), &holidays));
+    }
+
+    #[test]
+    fn an_evening_run_is_on_the_new_york_date() {
+        // 8pm EDT is already the next day in UTC
+        let run = Utc.ymd(2020, 7, 2).and_hms(0, 0, 0);
+        assert_eq!(trading_date(&run), day(2020, 7, 1));
+    }
+
+    #[test]
+    fn the_bounds_follow_daylight_saving_changes() {
+        let hours = |date| {
+            let (start, end) = trading_day_bounds(date);
+            (end + Duration::microseconds(1) - start).num_hours()
+        };
+        // the clocks go forward on 2020-03-08 and back on 2020-11-01
+        assert_eq!(hours(day(2020, 3, 8)), 23);
+        assert_eq!(hours(day(2020, 11, 1)), 25);
+
+        let (start, end) = trading_day_bounds(day(2020, 3, 8));
+        assert_eq!(start.to_rfc3339(), "2020-03-08T05:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2020-03-09T03:59:59.999999+00:00");
+        let (start, end) = trading_day_bounds(day(2020, 11, 1));
+        assert_eq!(start.to_rfc3339(), "2020-11-01T04:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2020-11-02T04:59:59.999999+00:00");
+    }
+}
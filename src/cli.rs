@@ -0,0 +1,520 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::buckets::BucketThresholds;
+use crate::calendar;
+use crate::db::{Columns, MAX_INSERT_CHUNK_SIZE};
+use crate::numbers::{DecimalStyle, PercentageStorage, PercentageUnit, SignSource};
+
+/// Command line options. With no subcommand we do a single scrape run.
+#[derive(Debug, Parser)]
+#[command(about = "Scrape the after-market movers and store them in Postgres")]
+pub struct Opts {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// How to print the scraped (or queried) data to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Debug, global = true)]
+    pub output: OutputFormat,
+
+    /// Also write the data, in the --output format, to this file. It's only
+    /// replaced once the run has succeeded and the new file is complete.
+    #[arg(long, global = true)]
+    pub out_file: Option<PathBuf>,
+
+    /// After each scrape, overwrite this file with a JSON object saying
+    /// whether it worked, how many rows it got, how long it took, its run id
+    /// and time, and its error if it failed
+    #[arg(long, value_name = "PATH")]
+    pub status_file: Option<PathBuf>,
+
+    /// Write this field under HEADER in --output csv, e.g. --csv-column
+    /// symbol=ticker. Given at all, only the listed fields are written, in
+    /// the order given. Fields: symbol, percentage, date, is_halted, bucket.
+    #[arg(long = "csv-column", value_name = "FIELD=HEADER", value_parser = parse_csv_column, global = true)]
+    pub csv_columns: Vec<CsvColumn>,
+
+    /// Show dates in this IANA timezone (e.g. America/Chicago) when printing
+    /// or writing --out-file. Stored dates stay in UTC.
+    #[arg(long, value_name = "IANA", global = true)]
+    pub display_tz: Option<Tz>,
+
+    /// How the after_market table's percentage column stores percentages
+    #[arg(long, value_enum, default_value_t = PercentageStorage::Float, global = true)]
+    pub percentage_storage: PercentageStorage,
+
+    /// The after_market table's columns, comma separated, for a table made
+    /// before all of them existed, e.g. --db-columns symbol,percentage,date.
+    /// symbol, percentage and date are required.
+    #[arg(long, value_name = "COLUMNS", value_parser = parse_db_columns, default_value_t = Columns::all(), global = true)]
+    pub db_columns: Columns,
+
+    /// Don't print the data to stdout, and only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log more of what we're doing (repeat for even more, -vv for trace)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Also store the run's gainers/losers counts in the run_summaries table
+    #[arg(long)]
+    pub store_summary: bool,
+
+    /// Also scrape an index's percentage change, stored as LABEL, from the
+    /// element matching the CSS SELECTOR, e.g. --index 'VIX=div#vixQuote'
+    /// (can be given more than once)
+    #[arg(long = "index", value_name = "LABEL=SELECTOR", value_parser = parse_index)]
+    pub indices: Vec<IndexConfig>,
+
+    /// Fail the scrape (so it's retried) unless every one of these index
+    /// labels was captured, e.g. --require-indices S&P,Dow. Otherwise
+    /// indices are best-effort.
+    #[arg(long, value_name = "LABELS", value_delimiter = ',')]
+    pub require_indices: Vec<String>,
+
+    /// How the source formats its numbers' thousands and decimal separators
+    #[arg(long, value_enum, default_value_t = DecimalStyle::Us)]
+    pub decimal_style: DecimalStyle,
+
+    /// Whether the source writes its percentages in percent ("7.06") or as a
+    /// fraction of one ("0.0706"); either way they're stored in percent
+    #[arg(long, value_enum, default_value_t = PercentageUnit::Percent)]
+    pub percentage_unit: PercentageUnit,
+
+    /// Find the movers table by its "Gainers & Losers" heading instead of by
+    /// its container's id, for when the id changes
+    #[arg(long)]
+    pub find_movers_by_heading: bool,
+
+    /// Warn when more than this fraction (0 to 1) of a run's percentages are
+    /// exactly zero or all the same, which usually means the wrong column was
+    /// read
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction)]
+    pub misparse_fraction: Option<f64>,
+
+    /// Fail the run instead of only warning when --misparse-fraction trips
+    #[arg(long, requires = "misparse_fraction")]
+    pub fail_on_misparse: bool,
+
+    /// Fail the run without storing anything if fewer than this many movers
+    /// were scraped, since a near-empty table usually means the page broke.
+    /// Index rows like the S&P don't count.
+    #[arg(long, value_name = "N")]
+    pub min_rows: Option<usize>,
+
+    /// Whether a mover missing its percentage fails the scrape or is stored
+    /// without one. Symbols are always required.
+    #[arg(long, value_enum, default_value_t = FieldPolicy::Required)]
+    pub percentage_policy: FieldPolicy,
+
+    /// Where movers' percentages get their sign from. With `class`, a text
+    /// sign that disagrees with the cell's class is logged and overridden.
+    #[arg(long, value_enum, default_value_t = SignSource::Text)]
+    pub sign_source: SignSource,
+
+    /// When a movers row fails to parse, log its HTML (rebuilt from the node
+    /// tree) along with the error. Rows can be large, so it's off by default.
+    #[arg(long)]
+    pub debug_row_html: bool,
+
+    /// Print which selector and text produced each scraped field to stderr
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Treat the symbol ALIAS as SYMBOL, e.g. --symbol-alias BRK-B=BRK.B
+    /// (can be given more than once)
+    #[arg(long = "symbol-alias", value_name = "ALIAS=SYMBOL", value_parser = parse_symbol_alias)]
+    pub symbol_aliases: Vec<(String, String)>,
+
+    /// The SMALL,BIG percentage changes separating the flat, gain/loss and
+    /// big gain/big loss buckets, applied the same way up and down
+    #[arg(long, value_name = "SMALL,BIG", default_value = "2,5", value_parser = parse_bucket_thresholds)]
+    pub bucket_thresholds: BucketThresholds,
+
+    /// How many decimal places to round percentages to before storing and
+    /// printing them (halves round to even)
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(0..=10))]
+    pub precision: u32,
+
+    /// Scrape and print the data without touching the database
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Take a Postgres advisory lock on today's trading day before scraping,
+    /// and skip the run if another instance already holds it, so overlapping
+    /// runs don't scrape and store the same day twice
+    #[arg(long)]
+    pub lock_trading_day: bool,
+
+    /// Check the database is reachable before scraping, failing straight
+    /// away instead of after the scrape if it isn't
+    #[arg(long)]
+    pub require_db: bool,
+
+    /// If the database connection turns out to have been dropped when we go
+    /// to store the run, reconnect and retry the step that failed, up to this
+    /// many times in all
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub db_reconnects: u32,
+
+    /// Exit without scraping when today (in New York) is a weekend or one
+    /// of the --holiday dates
+    #[arg(long)]
+    pub skip_non_trading_days: bool,
+
+    /// Skip the scrape if a run has already been stored for today's trading
+    /// day, e.g. when cron fires twice
+    #[arg(long)]
+    pub once_per_day: bool,
+
+    /// A date the market is closed, as YYYY-MM-DD (can be given more than
+    /// once, or as a comma-separated list)
+    #[arg(long = "holiday", value_name = "DATE", value_delimiter = ',', value_parser = parse_day)]
+    pub holidays: Vec<NaiveDate>,
+
+    /// How to store the scraped data
+    #[arg(long, value_enum, default_value_t = StoreMode::Rows)]
+    pub store_mode: StoreMode,
+
+    /// Only store rows whose percentage moved by more than EPSILON since the
+    /// symbol's last stored row today (with `--store-mode rows` and `copy`)
+    #[arg(long, value_name = "EPSILON")]
+    pub skip_unchanged: Option<f64>,
+
+    /// The most rows to put in a single INSERT statement; bigger batches are
+    /// split up, but still committed together
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..=MAX_INSERT_CHUNK_SIZE))]
+    pub insert_chunk_size: u64,
+
+    /// After inserting, read the rows back and check they match what we sent
+    /// (only applies to `--store-mode rows` and `copy`)
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Before storing, look up the after_market.percentage column's type and,
+    /// if it's a NUMERIC(precision, scale), round percentages to its scale and
+    /// fail on any that won't fit (only applies to `--store-mode rows` and
+    /// `copy`)
+    #[arg(long)]
+    pub check_column_scale: bool,
+
+    /// How many times to re-run a failed scrape from scratch (with a fresh
+    /// browser) before giving up
+    #[arg(long, default_value_t = 0)]
+    pub scrape_retries: u32,
+
+    /// Before reading the movers table, wait until its row count has stayed
+    /// the same for this many polls in a row (0 doesn't wait). Not supported
+    /// with --find-movers-by-heading.
+    #[arg(long, default_value_t = 0)]
+    pub stable_rounds: u32,
+
+    /// How often to poll the movers table's row count, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub stable_poll_ms: u64,
+
+    /// How many seconds to wait for the row count to settle before reading
+    /// the table anyway
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub stable_timeout: u64,
+
+    /// Visit the site's front page, briefly, before the movers page, since
+    /// a cold session is sometimes served a slimmer page
+    #[arg(long)]
+    pub warm_up: bool,
+
+    /// Load the page from this saved HTML file (a path or a file:// URL)
+    /// instead of AFTER_MARKET_URL, so nothing touches the network. The
+    /// scraped rows are still stored unless --dry-run is given too.
+    #[arg(long, value_name = "PATH", value_parser = parse_source_file)]
+    pub source_file: Option<String>,
+
+    /// How many seconds describing and parsing the page's node tree may take,
+    /// once the movers table has loaded, before the scrape fails
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    pub parse_timeout: u64,
+
+    /// Run Chrome with a visible window and, if the scrape fails, leave it
+    /// open with DevTools listening so the page can be inspected
+    #[arg(long)]
+    pub keep_browser_on_error: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Read previously stored rows back out of the database
+    Query(QueryOpts),
+    /// Compare the percentages stored on two trading days, symbol by symbol
+    Compare(CompareOpts),
+    /// Save the movers table's DOM as JSON, for use as a parsing fixture
+    #[command(hide = true)]
+    DumpDom(DumpDomOpts),
+    /// Parse a DOM saved by dump-dom and print the rows it yields, without a
+    /// browser or the database
+    Replay(ReplayOpts),
+    /// For each symbol in the latest run, how many trading days it had been
+    /// since the symbol was last a mover
+    LastSeen(LastSeenOpts),
+    /// Print the SQL that creates the after_market table for
+    /// --percentage-storage
+    Schema,
+}
+
+#[derive(Debug, Args)]
+pub struct LastSeenOpts {
+    /// Look up this symbol instead of the latest run's (can be given more
+    /// than once)
+    #[arg(long = "symbol")]
+    pub symbols: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayOpts {
+    /// The JSON file dump-dom wrote
+    #[arg(long)]
+    pub fixture: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct DumpDomOpts {
+    /// Where to write the JSON
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryOpts {
+    /// Only return rows for this ticker symbol
+    #[arg(long)]
+    pub symbol: Option<String>,
+
+    /// Only return rows stored by this run, as logged when it started
+    #[arg(long)]
+    pub run_id: Option<Uuid>,
+
+    /// Return at most this many rows, newest first
+    #[arg(long)]
+    pub limit: Option<i64>,
+
+    /// Only return rows from this time onward (RFC3339, or YYYY-MM-DD for
+    /// the start of that New York trading day)
+    #[arg(long, value_parser = parse_since)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only return rows up to this time (RFC3339, or YYYY-MM-DD for the
+    /// whole of that New York trading day)
+    #[arg(long, value_parser = parse_until)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// A field of AfterMarketPriceData that can be a CSV column
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum CsvField {
+    Symbol,
+    Percentage,
+    Date,
+    IsHalted,
+    Bucket,
+}
+
+/// One column of --output csv: which field, and what to call it
+#[derive(Clone, Debug)]
+pub struct CsvColumn {
+    pub field: CsvField,
+    pub header: String,
+}
+
+/// An extra market index to scrape, and where on the page to find it
+#[derive(Clone, Debug)]
+pub struct IndexConfig {
+    pub label: String,
+    pub selector: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CompareOpts {
+    /// The first trading day, as YYYY-MM-DD
+    #[arg(long, value_parser = parse_day)]
+    pub day_a: NaiveDate,
+
+    /// The second trading day, as YYYY-MM-DD
+    #[arg(long, value_parser = parse_day)]
+    pub day_b: NaiveDate,
+}
+
+/// What to do when a field's selector matches nothing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FieldPolicy {
+    /// Fail the scrape
+    Required,
+    /// Log it and store the field as none
+    Optional,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The whole batch, printed with `{:?}`
+    Debug,
+    /// One JSON object per line, flushed as each is written
+    Jsonl,
+    /// Comma-separated values with a header row
+    Csv,
+    /// GitHub-flavored Markdown tables of the gainers, losers and indices
+    Markdown,
+}
+
+/// Parse a "LABEL=SELECTOR" pair. Only the first `=` splits them, since
+/// selectors can contain their own.
+fn parse_index(s: &str) -> Result<IndexConfig, String> {
+    match s.split_once('=') {
+        Some((label, selector)) if !label.trim().is_empty() && !selector.trim().is_empty() => {
+            Ok(IndexConfig {
+                label: label.trim().to_string(),
+                selector: selector.trim().to_string(),
+            })
+        }
+        _ => Err(format!("{:?} is not of the form LABEL=SELECTOR", s)),
+    }
+}
+
+/// Parse a "FIELD=HEADER" CSV column mapping
+fn parse_csv_column(s: &str) -> Result<CsvColumn, String> {
+    match s.split_once('=') {
+        Some((field, header)) if !header.trim().is_empty() => Ok(CsvColumn {
+            field: CsvField::from_str(field.trim(), false)?,
+            header: header.trim().to_string(),
+        }),
+        _ => Err(format!("{:?} is not of the form FIELD=HEADER", s)),
+    }
+}
+
+/// Parse a comma separated list of after_market columns
+fn parse_db_columns(s: &str) -> Result<Columns, String> {
+    let names: Vec<&str> = s.split(',').map(str::trim).collect();
+    Columns::enabled(&names)
+}
+
+/// Parse a fraction between 0 and 1
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(format!("{:?} is not a number from 0 to 1", s)),
+    }
+}
+
+/// Parse "SMALL,BIG" bucket thresholds, which have to be positive and in
+/// that order
+fn parse_bucket_thresholds(s: &str) -> Result<BucketThresholds, String> {
+    let invalid = || format!("{:?} is not of the form SMALL,BIG with 0 < SMALL < BIG", s);
+    let (small, big) = s.split_once(',').ok_or_else(invalid)?;
+    let small: f64 = small.trim().parse().map_err(|_| invalid())?;
+    let big: f64 = big.trim().parse().map_err(|_| invalid())?;
+    if !(0.0 < small && small < big && big.is_finite()) {
+        return Err(invalid());
+    }
+
+    Ok(BucketThresholds { small, big })
+}
+
+/// Parse an "ALIAS=SYMBOL" pair, normalizing both sides the way the scraped
+/// symbols will be
+fn parse_symbol_alias(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((alias, symbol)) if !alias.trim().is_empty() && !symbol.trim().is_empty() => {
+            Ok((alias.trim().to_uppercase(), symbol.trim().to_uppercase()))
+        }
+        _ => Err(format!("{:?} is not of the form ALIAS=SYMBOL", s)),
+    }
+}
+
+/// Turn a --source-file path into the file:// URL the browser will load,
+/// leaving one that's already a file:// URL as it is
+fn parse_source_file(s: &str) -> Result<String, String> {
+    if s.starts_with("file://") {
+        return Ok(s.to_string());
+    }
+    let path = std::fs::canonicalize(s).map_err(|e| format!("{:?}: {}", s, e))?;
+    Ok(format!("file://{}", path.display()))
+}
+
+fn parse_day(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| format!("{:?} is not a YYYY-MM-DD date", s))
+}
+
+/// Parse an RFC3339 timestamp, or a bare date meaning the start of that
+/// trading day, the same day `compare` and --once-per-day would count it as
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(s, |day| calendar::trading_day_bounds(day).0)
+}
+
+/// Parse an RFC3339 timestamp, or a bare date meaning the end of that
+/// trading day
+fn parse_until(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(s, |day| calendar::trading_day_bounds(day).1)
+}
+
+fn parse_date<F>(s: &str, bound: F) -> Result<DateTime<Utc>, String>
+where
+    F: Fn(NaiveDate) -> DateTime<Utc>,
+{
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(day) => Ok(bound(day)),
+        Err(_) => Err(format!(
+            "{:?} is not an RFC3339 timestamp or YYYY-MM-DD date",
+            s
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StoreMode {
+    /// One row per ticker in the after_market table
+    Rows,
+    /// Same rows as `rows`, but loaded with one COPY instead of INSERTs,
+    /// which is quicker for very large batches
+    Copy,
+    /// The whole run as a single JSONB row in the snapshots table
+    Snapshot,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_dates_cover_the_new_york_trading_day() {
+        // EDT, so the day runs 04:00 UTC to 04:00 UTC the next day
+        assert_eq!(
+            parse_since("2020-07-01").unwrap().to_rfc3339(),
+            "2020-07-01T04:00:00+00:00"
+        );
+        assert_eq!(
+            parse_until("2020-07-01").unwrap().to_rfc3339(),
+            "2020-07-02T03:59:59.999999+00:00"
+        );
+        // an 8:30pm EDT run is stored on the next UTC date, but is still in D
+        let run = parse_since("2020-07-02T00:30:00Z").unwrap();
+        assert!(parse_since("2020-07-01").unwrap() <= run);
+        assert!(run <= parse_until("2020-07-01").unwrap());
+    }
+
+    #[test]
+    fn timestamps_are_taken_as_given() {
+        assert_eq!(
+            parse_since("2020-07-01T12:00:00-04:00")
+                .unwrap()
+                .to_rfc3339(),
+            "2020-07-01T16:00:00+00:00"
+        );
+        assert!(parse_until("July 1st").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::cli::OutputFormat;
+use crate::output::{csv_field, markdown_cell, write_jsonl_record};
+use crate::AfterMarketPriceData;
+
+/// DayComparison lines up one symbol's stored percentage on two trading days.
+/// A symbol only stored on one of the days has `None` for the other.
+#[derive(Debug, Serialize)]
+pub struct DayComparison {
+    symbol: String,
+    day_a: Option<f64>,
+    day_b: Option<f64>,
+}
+
+impl DayComparison {
+    /// How much the percentage moved from day A to day B, if it was stored on both
+    pub fn difference(&self) -> Option<f64> {
+        match (self.day_a, self.day_b) {
+            (Some(a), Some(b)) => Some(b - a),
+            _ => None,
+        }
+    }
+}
+
+/// Pair up each symbol's percentage on the two days, ordered by symbol. The
+/// rows are expected newest first (the way the query returns them), so if a
+/// day was scraped more than once its last scrape wins. Halted rows without
+/// a percentage don't count.
+pub fn compare_days(
+    day_a: &[AfterMarketPriceData],
+    day_b: &[AfterMarketPriceData],
+) -> Vec<DayComparison> {
+    let mut by_symbol: BTreeMap<&str, (Option<f64>, Option<f64>)> = BTreeMap::new();
+    for d in day_a.iter() {
+        let entry = by_symbol.entry(&d.symbol).or_default();
+        if let Some(p) = d.percentage {
+            entry.0.get_or_insert(p.value());
+        }
+    }
+    for d in day_b.iter() {
+        let entry = by_symbol.entry(&d.symbol).or_default();
+        if let Some(p) = d.percentage {
+            entry.1.get_or_insert(p.value());
+        }
+    }
+
+    by_symbol
+        .into_iter()
+        .map(|(symbol, (day_a, day_b))| DayComparison {
+            symbol: symbol.to_string(),
+            day_a,
+            day_b,
+        })
+        .collect()
+}
+
+/// Print the comparison to stdout: as JSON lines or CSV if asked for, and
+/// otherwise as a table of the symbols on both days followed by the symbols
+/// unique to each day
+pub fn write_comparison(
+    comparisons: &[DayComparison],
+    day_a: NaiveDate,
+    day_b: NaiveDate,
+    format: OutputFormat,
+) -> Result<(), failure::Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match format {
+        OutputFormat::Jsonl => {
+            for c in comparisons.iter() {
+                write_jsonl_record(&mut out, c)?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "symbol,{},{},difference", day_a, day_b)?;
+            for c in comparisons.iter() {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    csv_field(&c.symbol),
+                    optional_cell(c.day_a),
+                    optional_cell(c.day_b),
+                    optional_cell(c.difference())
+                )?;
+            }
+        }
+        OutputFormat::Markdown => {
+            writeln!(out, "| Symbol | {} | {} | Difference |", day_a, day_b)?;
+            writeln!(out, "| :--- | ---: | ---: | ---: |")?;
+            for c in comparisons.iter() {
+                writeln!(
+                    out,
+                    "| {} | {} | {} | {} |",
+                    markdown_cell(&c.symbol),
+                    optional_cell(c.day_a),
+                    optional_cell(c.day_b),
+                    optional_cell(c.difference())
+                )?;
+            }
+        }
+        OutputFormat::Debug => {
+            writeln!(
+                out,
+                "{:<10} {:>12} {:>12} {:>12}",
+                "symbol", day_a, day_b, "difference"
+            )?;
+            for c in comparisons.iter() {
+                if let (Some(a), Some(b), Some(difference)) = (c.day_a, c.day_b, c.difference()) {
+                    writeln!(
+                        out,
+                        "{:<10} {:>12.2} {:>12.2} {:>+12.2}",
+                        c.symbol, a, b, difference
+                    )?;
+                }
+            }
+
+            let only_a: Vec<&str> = comparisons
+                .iter()
+                .filter(|c| c.day_b.is_none())
+                .map(|c| c.symbol.as_str())
+                .collect();
+            let only_b: Vec<&str> = comparisons
+                .iter()
+                .filter(|c| c.day_a.is_none())
+                .map(|c| c.symbol.as_str())
+                .collect();
+            writeln!(out)?;
+            writeln!(out, "only on {}: {}", day_a, only_a.join(", "))?;
+            writeln!(out, "only on {}: {}", day_b, only_b.join(", "))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn optional_cell(value: Option<f64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,1086 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use failure::bail;
+use log::{error, info, warn};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use postgres::error::{
+    ADMIN_SHUTDOWN, CONNECTION_FAILURE, T_R_DEADLOCK_DETECTED, T_R_SERIALIZATION_FAILURE,
+};
+use postgres::rows::Row;
+use postgres::transaction::Transaction;
+use postgres::types::ToSql;
+use postgres::{Connection, GenericConnection, TlsMode};
+use postgres_openssl::OpenSsl;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::buckets::Bucket;
+use crate::calendar;
+use crate::cli::{QueryOpts, StoreMode};
+use crate::numbers::{from_basis_points, Percentage, PercentageStorage};
+use crate::secrets;
+use crate::summary::RunSummary;
+use crate::AfterMarketPriceData;
+
+const TABLE_NAME: &str = "after_market";
+const RUN_SUMMARIES_TABLE_NAME: &str = "run_summaries";
+const SNAPSHOTS_TABLE_NAME: &str = "snapshots";
+
+/// What kind of value an after_market column holds
+#[derive(Clone, Copy, Debug)]
+enum ColumnType {
+    Symbol,
+    Percentage,
+    Timestamp,
+    Flag,
+    Bucket,
+    RunId,
+}
+
+impl ColumnType {
+    /// The column's type and constraints in a CREATE TABLE
+    fn definition(self, storage: PercentageStorage) -> &'static str {
+        match (self, storage) {
+            (ColumnType::Symbol, _) => "VARCHAR(10)         NOT NULL",
+            (ColumnType::Percentage, PercentageStorage::Float) => "DOUBLE PRECISION",
+            (ColumnType::Percentage, PercentageStorage::BasisPoints) => "INTEGER",
+            (ColumnType::Timestamp, _) => "TIMESTAMP WITH TIME ZONE",
+            (ColumnType::Flag, _) => "BOOLEAN             NOT NULL DEFAULT FALSE",
+            (ColumnType::Bucket, _) => "VARCHAR(10)",
+            (ColumnType::RunId, _) => "UUID",
+        }
+    }
+
+    /// The INSERT placeholder for parameter `n` of this type. The driver
+    /// can't bind a Uuid, so run ids are sent as text and cast back, and it
+    /// only binds a percentage as the exact type we send, so that's cast to
+    /// whatever the column is (e.g. a NUMERIC) by Postgres.
+    fn placeholder(self, n: usize, storage: PercentageStorage) -> String {
+        match self {
+            ColumnType::RunId => format!("${}::text::uuid", n),
+            ColumnType::Percentage => format!("${}::{}", n, percentage_sql_type(storage)),
+            _ => format!("${}", n),
+        }
+    }
+
+    /// Whether the column gets an index. A flag has too few distinct values
+    /// for one to help.
+    fn indexed(self) -> bool {
+        !matches!(self, ColumnType::Flag)
+    }
+
+    /// The value `d` stores in this column. The driver can't bind a Uuid, so
+    /// the run id is passed in already written out as text.
+    fn value(
+        self,
+        d: &AfterMarketPriceData,
+        storage: PercentageStorage,
+        run_id: &str,
+    ) -> ColumnValue {
+        match (self, storage) {
+            (ColumnType::Symbol, _) => ColumnValue::Text(d.symbol.clone()),
+            (ColumnType::Percentage, PercentageStorage::Float) => {
+                ColumnValue::Float(d.percentage.map(Percentage::value))
+            }
+            (ColumnType::Percentage, PercentageStorage::BasisPoints) => {
+                ColumnValue::Integer(d.percentage.map(Percentage::basis_points))
+            }
+            (ColumnType::Timestamp, _) => ColumnValue::Timestamp(d.date),
+            (ColumnType::Flag, _) => ColumnValue::Flag(d.is_halted),
+            (ColumnType::Bucket, _) => ColumnValue::Label(d.bucket.map(Bucket::label)),
+            (ColumnType::RunId, _) => ColumnValue::Text(run_id.to_string()),
+        }
+    }
+}
+
+/// A row's value for one after_market column, as the INSERTs bind it and
+/// COPY writes it
+#[derive(Debug)]
+enum ColumnValue {
+    Text(String),
+    Label(Option<&'static str>),
+    Float(Option<f64>),
+    Integer(Option<i32>),
+    Timestamp(DateTime<Utc>),
+    Flag(bool),
+}
+
+impl ColumnValue {
+    /// The value as an INSERT parameter
+    fn into_param(self) -> Box<dyn ToSql> {
+        match self {
+            ColumnValue::Text(s) => Box::new(s),
+            ColumnValue::Label(l) => Box::new(l),
+            ColumnValue::Float(f) => Box::new(f),
+            ColumnValue::Integer(i) => Box::new(i),
+            ColumnValue::Timestamp(t) => Box::new(t),
+            ColumnValue::Flag(b) => Box::new(b),
+        }
+    }
+
+    /// The value as a field of COPY's text format, where \N is NULL
+    fn copy_text(&self) -> String {
+        match self {
+            ColumnValue::Text(s) => copy_text_field(s),
+            ColumnValue::Label(l) => l.map_or("\\N".to_string(), copy_text_field),
+            ColumnValue::Float(f) => f.map_or("\\N".to_string(), |f| f.to_string()),
+            ColumnValue::Integer(i) => i.map_or("\\N".to_string(), |i| i.to_string()),
+            ColumnValue::Timestamp(t) => t.to_rfc3339(),
+            ColumnValue::Flag(b) => b.to_string(),
+        }
+    }
+}
+
+/// The after_market table's columns, in the order the CREATE TABLE lists them
+/// and the INSERTs and COPY bind them. Adding a field to AfterMarketPriceData
+/// means adding it here, with a ColumnType saying what value it stores.
+const COLUMNS: &[(&str, ColumnType)] = &[
+    ("symbol", ColumnType::Symbol),
+    ("percentage", ColumnType::Percentage),
+    ("date", ColumnType::Timestamp),
+    ("is_halted", ColumnType::Flag),
+    ("bucket", ColumnType::Bucket),
+    ("run_id", ColumnType::RunId),
+];
+
+/// Rows are unique per symbol per scrape
+const PRIMARY_KEY: &[&str] = &["symbol", "date"];
+
+/// The columns every after_market table has, however old its schema
+const REQUIRED_COLUMNS: &[&str] = &["symbol", "percentage", "date"];
+
+/// The most values each row of an after_market INSERT binds
+const INSERT_COLUMNS: usize = COLUMNS.len();
+
+/// The after_market columns a deployment's table has, in COLUMNS order. A
+/// table from before some of them were added leaves those out, and rows are
+/// stored and read back without them.
+#[derive(Clone, Debug)]
+pub struct Columns(Vec<(&'static str, ColumnType)>);
+
+impl Columns {
+    /// Every column in COLUMNS
+    pub fn all() -> Columns {
+        Columns(COLUMNS.to_vec())
+    }
+
+    /// Only the columns named in `names`, which have to include the
+    /// required ones
+    pub fn enabled(names: &[&str]) -> Result<Columns, String> {
+        if let Some(unknown) = names.iter().find(|n| !COLUMNS.iter().any(|(c, _)| c == *n)) {
+            return Err(format!(
+                "there's no {:?} column, the columns are {}",
+                unknown,
+                Columns::all()
+            ));
+        }
+        if let Some(missing) = REQUIRED_COLUMNS.iter().find(|c| !names.contains(c)) {
+            return Err(format!("the {} column is required", missing));
+        }
+        Ok(Columns(
+            COLUMNS
+                .iter()
+                .filter(|(c, _)| names.contains(c))
+                .copied()
+                .collect(),
+        ))
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|(c, _)| *c == name)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(&'static str, ColumnType)> {
+        self.0.iter()
+    }
+
+    /// The columns, comma separated, for an INSERT or COPY
+    fn list(&self) -> String {
+        let names: Vec<&str> = self.iter().map(|(name, _)| *name).collect();
+        names.join(", ")
+    }
+}
+
+impl fmt::Display for Columns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.iter().map(|(name, _)| *name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// The statements that create the after_market table, with just `columns`,
+/// and its indices, for percentages stored as `storage`
+pub fn create_table_sql(storage: PercentageStorage, columns: &Columns) -> String {
+    let mut sql = format!("CREATE TABLE IF NOT EXISTS {}(\n", TABLE_NAME);
+    for (name, ty) in columns.iter() {
+        sql.push_str(&format!("   {:<17}{},\n", name, ty.definition(storage)));
+    }
+    sql.push_str(&format!(
+        "   {:<17}({})\n);\n\n",
+        "PRIMARY KEY",
+        PRIMARY_KEY.join(", ")
+    ));
+    for (name, _) in columns.iter().filter(|(_, ty)| ty.indexed()) {
+        sql.push_str(&format!("CREATE INDEX ON {} ({});\n", TABLE_NAME, name));
+    }
+    sql
+}
+
+/// The most rows one INSERT can hold without going over the 32767 bind
+/// parameters the driver will send in a single statement (it counts them in
+/// an i16, though Postgres itself allows 65535)
+pub const MAX_INSERT_CHUNK_SIZE: u64 = i16::MAX as u64 / INSERT_COLUMNS as u64;
+
+/// The first key of our advisory locks, so they can't collide with locks
+/// another application takes on the same database
+const ADVISORY_LOCK_CLASS: i32 = 0x6166_746d;
+
+/// How many times we'll attempt a transaction that keeps losing out to a
+/// concurrent writer before giving up on it
+const MAX_TRANSACTION_ATTEMPTS: u32 = 4;
+
+/// How long to wait before the first retry, doubled after every failed attempt
+const TRANSACTION_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Connect to the Postgres database at DATABASE_URL, with the tables looked
+/// up in the DB_SCHEMA schema if that's set
+pub fn connect() -> Result<Connection, failure::Error> {
+    // Create Ssl postgres connector without verification as required to connect to Heroku.
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let mut connect = OpenSsl::from(builder.build());
+    connect.danger_disable_hostname_verification(true);
+    let conn = Connection::connect(
+        secrets::read_secret("DATABASE_URL")?,
+        TlsMode::Require(&connect),
+    )?;
+
+    if let Ok(schema) = env::var("DB_SCHEMA") {
+        // SET can't take the schema as a parameter, so it has to be safe to
+        // put in the statement as it is
+        if !is_identifier(&schema) {
+            bail!("DB_SCHEMA {:?} isn't a valid schema name", schema);
+        }
+        conn.batch_execute(&format!("SET search_path TO {}", schema))?;
+    }
+
+    Ok(conn)
+}
+
+/// Whether `s` is a plain, unquoted SQL identifier like our table names:
+/// letters, digits and underscores, not starting with a digit
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Check the database is actually answering queries, not just accepting
+/// connections
+pub fn ping_db(conn: &Connection) -> Result<(), postgres::Error> {
+    conn.query("SELECT 1", &[])?;
+    Ok(())
+}
+
+/// Insert the whole batch in a single transaction, so a run is stored either
+/// completely or not at all. Rows go in as multi-row INSERTs of at most
+/// `chunk_size` rows each, all tagged with `run_id`, and `summary` (if there
+/// is one) goes in with them.
+pub fn insert_after_market_data_into_db(
+    conn: &Connection,
+    after_market_data: &[AfterMarketPriceData],
+    chunk_size: usize,
+    storage: PercentageStorage,
+    run_id: &Uuid,
+    columns: &Columns,
+    summary: Option<&RunSummary>,
+) -> Result<(), postgres::Error> {
+    let statements = insert_statements(after_market_data, chunk_size, storage, run_id, columns);
+    with_transaction_retries(conn, |tx| {
+        for statement in statements.iter() {
+            let params: Vec<&dyn ToSql> = statement.params.iter().map(|p| &**p).collect();
+            tx.execute(&statement.sql, &params)?;
+        }
+        if let Some(summary) = summary {
+            insert_run_summary(tx, summary)?;
+        }
+        Ok(())
+    })
+}
+
+/// One multi-row INSERT, and the values it binds in order
+struct InsertStatement {
+    sql: String,
+    params: Vec<Box<dyn ToSql>>,
+}
+
+/// Build the INSERTs that store `after_market_data`'s `columns`, at most
+/// `chunk_size` rows to each
+fn insert_statements(
+    after_market_data: &[AfterMarketPriceData],
+    chunk_size: usize,
+    storage: PercentageStorage,
+    run_id: &Uuid,
+    columns: &Columns,
+) -> Vec<InsertStatement> {
+    // the driver can't bind a Uuid, so it goes in as text and is cast back
+    let run_id = run_id.to_string();
+    after_market_data
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let mut values = Vec::with_capacity(chunk.len());
+            let mut params: Vec<Box<dyn ToSql>> = Vec::with_capacity(chunk.len() * columns.len());
+            for d in chunk.iter() {
+                let n = params.len();
+                let placeholders: Vec<String> = columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, ty))| ty.placeholder(n + i + 1, storage))
+                    .collect();
+                values.push(format!("({})", placeholders.join(", ")));
+                for (_, ty) in columns.iter() {
+                    params.push(ty.value(d, storage, &run_id).into_param());
+                }
+            }
+
+            InsertStatement {
+                sql: format!(
+                    "INSERT INTO {} ({}) VALUES {}",
+                    TABLE_NAME,
+                    columns.list(),
+                    values.join(", ")
+                ),
+                params,
+            }
+        })
+        .collect()
+}
+
+/// Load the whole batch with a single `COPY ... FROM STDIN` in one
+/// transaction, which is quicker than INSERTs for very large batches. The
+/// stored rows, and `summary`, are the same either way.
+pub fn copy_after_market_data_into_db(
+    conn: &Connection,
+    after_market_data: &[AfterMarketPriceData],
+    storage: PercentageStorage,
+    run_id: &Uuid,
+    columns: &Columns,
+    summary: Option<&RunSummary>,
+) -> Result<(), postgres::Error> {
+    let buf = copy_text(after_market_data, storage, run_id, columns);
+    with_transaction_retries(conn, |tx| {
+        let stmt = tx.prepare(&format!(
+            "COPY {} ({}) FROM STDIN",
+            TABLE_NAME,
+            columns.list()
+        ))?;
+        stmt.copy_in(&[], &mut buf.as_bytes())?;
+        if let Some(summary) = summary {
+            insert_run_summary(tx, summary)?;
+        }
+        Ok(())
+    })
+}
+
+/// The rows' `columns` in COPY's text format: tab separated, one row per line
+fn copy_text(
+    after_market_data: &[AfterMarketPriceData],
+    storage: PercentageStorage,
+    run_id: &Uuid,
+    columns: &Columns,
+) -> String {
+    let run_id = run_id.to_string();
+    let mut buf = String::new();
+    for d in after_market_data.iter() {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|(_, ty)| ty.value(d, storage, &run_id).copy_text())
+            .collect();
+        buf.push_str(&fields.join("\t"));
+        buf.push('\n');
+    }
+    buf
+}
+
+/// Escape the characters COPY's text format treats specially
+fn copy_text_field(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Store the whole run as one JSONB array in the snapshots table, rather than
+/// as a row per ticker, along with `summary` in the same transaction
+pub fn store_snapshot(
+    conn: &Connection,
+    after_market_data: &[AfterMarketPriceData],
+    date: &DateTime<Utc>,
+    summary: Option<&RunSummary>,
+) -> Result<(), failure::Error> {
+    let data = serde_json::to_value(after_market_data)?;
+    with_transaction_retries(conn, |tx| {
+        tx.execute(
+            &format!(
+                "INSERT INTO {} (date, data) VALUES ($1, $2)",
+                SNAPSHOTS_TABLE_NAME
+            ),
+            &[date, &data],
+        )?;
+        if let Some(summary) = summary {
+            insert_run_summary(tx, summary)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// The precision and scale of the after_market.percentage column when it's a
+/// NUMERIC with them set, or `None` for any other type
+pub fn percentage_column_numeric(conn: &Connection) -> Result<Option<(i32, i32)>, postgres::Error> {
+    // information_schema's numbers are a domain the driver won't read, so
+    // they're cast to plain integers
+    let rows = conn.query(
+        "SELECT numeric_precision::integer, numeric_scale::integer
+         FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1
+           AND column_name = 'percentage' AND data_type = 'numeric'",
+        &[&TABLE_NAME],
+    )?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    let row = rows.get(0);
+    match (row.get::<_, Option<i32>>(0), row.get::<_, Option<i32>>(1)) {
+        (Some(precision), Some(scale)) => Ok(Some((precision, scale))),
+        _ => Ok(None),
+    }
+}
+
+/// Try to take the advisory lock for the trading day `day` without waiting
+/// for it. Returns whether we got it; once we have, it's held until `conn` is
+/// closed.
+pub fn try_lock_trading_day(conn: &Connection, day: NaiveDate) -> Result<bool, postgres::Error> {
+    // e.g. 20191104, so the lock is easy to pick out in pg_locks
+    let day_key = day.year() * 10000 + day.month() as i32 * 100 + day.day() as i32;
+    let rows = conn.query(
+        "SELECT pg_try_advisory_lock($1, $2)",
+        &[&ADVISORY_LOCK_CLASS, &day_key],
+    )?;
+    Ok(rows.get(0).get(0))
+}
+
+/// Whether anything was stored, in the table `store_mode` writes to, at any
+/// time from `since` to `until`
+pub fn stored_between(
+    conn: &Connection,
+    store_mode: StoreMode,
+    since: &DateTime<Utc>,
+    until: &DateTime<Utc>,
+) -> Result<bool, postgres::Error> {
+    let table = match store_mode {
+        StoreMode::Rows | StoreMode::Copy => TABLE_NAME,
+        StoreMode::Snapshot => SNAPSHOTS_TABLE_NAME,
+    };
+    let rows = conn.query(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE date BETWEEN $1 AND $2)",
+            table
+        ),
+        &[since, until],
+    )?;
+    Ok(rows.get(0).get(0))
+}
+
+/// Read back the rows we just inserted for this run and check that every one
+/// of them made it into the table with the percentage we sent, logging each
+/// row that didn't
+pub fn verify_after_market_data(
+    conn: &Connection,
+    after_market_data: &[AfterMarketPriceData],
+    date: &DateTime<Utc>,
+    storage: PercentageStorage,
+) -> Result<(), failure::Error> {
+    let rows = conn.query(
+        &format!(
+            "SELECT symbol, percentage::{} FROM {} WHERE date = $1",
+            percentage_sql_type(storage),
+            TABLE_NAME
+        ),
+        &[date],
+    )?;
+    let stored: HashMap<String, Option<f64>> = rows
+        .iter()
+        .map(|row| (row.get(0), read_percentage(&row, 1, storage)))
+        .collect();
+
+    let mut mismatches = 0;
+    for d in after_market_data.iter() {
+        // basis points can't hold more than two decimal places, so compare
+        // against what storing our value should have given back
+        let expected = d.percentage.map(|p| match storage {
+            PercentageStorage::Float => p.value(),
+            PercentageStorage::BasisPoints => from_basis_points(p.basis_points()),
+        });
+        match stored.get(&d.symbol) {
+            Some(percentage) if *percentage == expected => {}
+            Some(percentage) => {
+                error!(
+                    "{} was stored with percentage {:?} but we inserted {:?}",
+                    d.symbol, percentage, expected
+                );
+                mismatches += 1;
+            }
+            None => {
+                error!(
+                    "{} is missing from {} after inserting it",
+                    d.symbol, TABLE_NAME
+                );
+                mismatches += 1;
+            }
+        }
+    }
+    if stored.len() != after_market_data.len() {
+        error!(
+            "found {} rows for {} but inserted {}",
+            stored.len(),
+            date,
+            after_market_data.len()
+        );
+        mismatches += 1;
+    }
+
+    if mismatches > 0 {
+        bail!(
+            "verification of the inserted rows found {} problems",
+            mismatches
+        );
+    }
+    info!("verified all {} inserted rows", after_market_data.len());
+    Ok(())
+}
+
+/// Read stored rows back out, newest first, narrowed down by whichever of the
+/// `query` subcommand's filters were given. Rows from a table without the
+/// is_halted or bucket column come back not halted and unbucketed.
+pub fn query_after_market_data(
+    conn: &Connection,
+    filter: &QueryOpts,
+    storage: PercentageStorage,
+    columns: &Columns,
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let mut clauses = Vec::new();
+    let mut params: Vec<&dyn ToSql> = Vec::new();
+
+    if let Some(symbol) = &filter.symbol {
+        params.push(symbol);
+        clauses.push(format!("symbol = ${}", params.len()));
+    }
+    let run_id = filter.run_id.map(|id| id.to_string());
+    if let Some(run_id) = &run_id {
+        if !columns.contains("run_id") {
+            bail!("can't filter by run id without the run_id column");
+        }
+        params.push(run_id);
+        clauses.push(format!("run_id = ${}::text::uuid", params.len()));
+    }
+    match (&filter.since, &filter.until) {
+        (Some(since), Some(until)) => {
+            params.push(since);
+            params.push(until);
+            clauses.push(format!(
+                "date BETWEEN ${} AND ${}",
+                params.len() - 1,
+                params.len()
+            ));
+        }
+        (Some(since), None) => {
+            params.push(since);
+            clauses.push(format!("date >= ${}", params.len()));
+        }
+        (None, Some(until)) => {
+            params.push(until);
+            clauses.push(format!("date <= ${}", params.len()));
+        }
+        (None, None) => {}
+    }
+
+    let optional = |name: &'static str, missing: &'static str| {
+        if columns.contains(name) {
+            name
+        } else {
+            missing
+        }
+    };
+    let mut query = format!(
+        "SELECT symbol, percentage::{}, date, {}, {} FROM {}",
+        percentage_sql_type(storage),
+        optional("is_halted", "FALSE"),
+        optional("bucket", "NULL::VARCHAR"),
+        TABLE_NAME
+    );
+    if !clauses.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(&clauses.join(" AND "));
+    }
+    query.push_str(" ORDER BY date DESC, symbol");
+    if let Some(limit) = &filter.limit {
+        params.push(limit);
+        query.push_str(&format!(" LIMIT ${}", params.len()));
+    }
+
+    let rows = conn.query(&query, &params)?;
+    let mut after_market_data = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        after_market_data.push(AfterMarketPriceData {
+            symbol: row.get(0),
+            percentage: read_percentage(&row, 1, storage)
+                .map(Percentage::new)
+                .transpose()?,
+            date: row.get(2),
+            is_halted: row.get(3),
+            bucket: read_bucket(&row, 4)?,
+        });
+    }
+    Ok(after_market_data)
+}
+
+/// The symbols stored by the most recent run
+pub fn latest_symbols(conn: &Connection) -> Result<Vec<String>, postgres::Error> {
+    let rows = conn.query(
+        &format!(
+            "SELECT DISTINCT symbol FROM {0} WHERE date = (SELECT MAX(date) FROM {0})
+             ORDER BY symbol",
+            TABLE_NAME
+        ),
+        &[],
+    )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// How many trading days before its latest appearance `symbol` was last
+/// stored, or `None` if the latest is its first. Only days on which anything
+/// was stored count as trading days, so a day we didn't scrape doesn't make
+/// the gap look longer.
+pub fn days_since_last_seen(
+    conn: &Connection,
+    symbol: &str,
+) -> Result<Option<i64>, postgres::Error> {
+    let rows = conn.query(
+        &format!(
+            "WITH days AS (
+                 SELECT DISTINCT (date AT TIME ZONE $2)::date AS day FROM {0}
+             ), seen AS (
+                 SELECT DISTINCT (date AT TIME ZONE $2)::date AS day FROM {0}
+                 WHERE symbol = $1
+             ), latest AS (
+                 SELECT MAX(day) AS day FROM seen
+             ), previous AS (
+                 SELECT MAX(day) AS day FROM seen WHERE day < (SELECT day FROM latest)
+             )
+             SELECT CASE WHEN previous.day IS NULL THEN NULL ELSE (
+                 SELECT COUNT(*) FROM days
+                 WHERE days.day > previous.day AND days.day <= (SELECT day FROM latest)
+             ) END
+             FROM previous",
+            TABLE_NAME
+        ),
+        &[&symbol, &calendar::trading_time_zone_name()],
+    )?;
+    Ok(rows.get(0).get(0))
+}
+
+/// The type percentages stored as `storage` are sent and read back as. The
+/// column itself may be another type, say a NUMERIC, so SQL casts to and from
+/// this one.
+fn percentage_sql_type(storage: PercentageStorage) -> &'static str {
+    match storage {
+        PercentageStorage::Float => "double precision",
+        PercentageStorage::BasisPoints => "integer",
+    }
+}
+
+/// Read the percentage in column `idx` of `row`, cast to
+/// `percentage_sql_type`, converting it back from basis points if that's how
+/// it was stored
+fn read_percentage(row: &Row, idx: usize, storage: PercentageStorage) -> Option<f64> {
+    match storage {
+        PercentageStorage::Float => row.get(idx),
+        PercentageStorage::BasisPoints => row.get::<_, Option<i32>>(idx).map(from_basis_points),
+    }
+}
+
+/// Read the bucket label in column `idx` of `row` back into a Bucket
+fn read_bucket(row: &Row, idx: usize) -> Result<Option<Bucket>, failure::Error> {
+    match row.get::<_, Option<String>>(idx) {
+        Some(label) => match Bucket::from_label(&label) {
+            Some(bucket) => Ok(Some(bucket)),
+            None => bail!("unknown bucket {:?} in {}", label, TABLE_NAME),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Store a run's gainers/losers counts in the run_summaries table, on
+/// whichever transaction is storing the run's rows
+fn insert_run_summary(
+    conn: &dyn GenericConnection,
+    summary: &RunSummary,
+) -> Result<(), postgres::Error> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (date, gainers, losers, ratio) VALUES ($1, $2, $3, $4)",
+            RUN_SUMMARIES_TABLE_NAME
+        ),
+        &[
+            &summary.date,
+            &(summary.gainers as i32),
+            &(summary.losers as i32),
+            &summary.ratio(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Run `f` inside a transaction and commit it, re-running the whole
+/// transaction with backoff if Postgres aborts it because of a serialization
+/// failure or deadlock with another writer. Any other error is returned
+/// immediately.
+fn with_transaction_retries<F>(conn: &Connection, mut f: F) -> Result<(), postgres::Error>
+where
+    F: FnMut(&Transaction) -> Result<(), postgres::Error>,
+{
+    let mut attempt = 1;
+    let mut backoff = TRANSACTION_RETRY_BACKOFF;
+    loop {
+        let result = conn.transaction().and_then(|tx| {
+            f(&tx)?;
+            tx.commit()
+        });
+
+        match result {
+            Err(ref e) if is_retryable(e) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                warn!(
+                    "transaction attempt {} of {} failed, retrying in {:?}: {}",
+                    attempt, MAX_TRANSACTION_ATTEMPTS, backoff, e
+                );
+                thread::sleep(backoff);
+                attempt += 1;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `e` means the connection itself is gone, as when the server drops
+/// one that sat idle too long, rather than that a statement failed on it.
+/// Retrying on the same connection won't help, but a fresh one might.
+pub fn is_connection_lost(e: &postgres::Error) -> bool {
+    if e.as_io().is_some() {
+        return true;
+    }
+    match e.code() {
+        Some(code) => *code == ADMIN_SHUTDOWN || *code == CONNECTION_FAILURE,
+        None => false,
+    }
+}
+
+/// Whether `e` is one of the errors Postgres expects the client to handle by
+/// retrying the transaction
+fn is_retryable(e: &postgres::Error) -> bool {
+    match e.code() {
+        Some(code) => *code == T_R_SERIALIZATION_FAILURE || *code == T_R_DEADLOCK_DETECTED,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_row;
+
+    #[test]
+    fn inserts_a_batch_bigger_than_the_chunk_size_in_several_statements() {
+        let after_market_data: Vec<AfterMarketPriceData> = (0..7)
+            .map(|i| test_row(&format!("T{}", i), Some(f64::from(i))))
+            .collect();
+        let statements = insert_statements(
+            &after_market_data,
+            3,
+            PercentageStorage::Float,
+            &Uuid::nil(),
+            &Columns::all(),
+        );
+
+        let rows: Vec<usize> = statements
+            .iter()
+            .map(|s| s.params.len() / INSERT_COLUMNS)
+            .collect();
+        assert_eq!(rows, vec![3, 3, 1]);
+        for (statement, rows) in statements.iter().zip(rows.iter()) {
+            assert_eq!(statement.params.len(), rows * INSERT_COLUMNS);
+            // each statement numbers its own parameters from $1
+            let last = format!("${}::text::uuid)", rows * INSERT_COLUMNS);
+            assert!(statement.sql.ends_with(&last), "{}", statement.sql);
+            assert!(
+                statement
+                    .sql
+                    .contains("VALUES ($1, $2::double precision, $3, "),
+                "{}",
+                statement.sql
+            );
+        }
+
+        // every row goes in once, in order
+        let symbols: Vec<String> = statements
+            .iter()
+            .flat_map(|s| s.params.iter().step_by(INSERT_COLUMNS))
+            .map(|p| format!("{:?}", p))
+            .collect();
+        let expected: Vec<String> = (0..7).map(|i| format!("{:?}", format!("T{}", i))).collect();
+        assert_eq!(symbols, expected);
+    }
+
+    #[test]
+    fn inserts_percentages_as_basis_points_when_asked() {
+        let after_market_data = vec![test_row("ABC", Some(7.06)), test_row("XYZ", None)];
+        let statements = insert_statements(
+            &after_market_data,
+            500,
+            PercentageStorage::BasisPoints,
+            &Uuid::nil(),
+            &Columns::all(),
+        );
+        assert_eq!(statements.len(), 1);
+        let params = &statements[0].params;
+        assert_eq!(format!("{:?}", params[1]), "Some(706)");
+        assert_eq!(format!("{:?}", params[INSERT_COLUMNS + 1]), "None");
+    }
+
+    #[test]
+    fn copies_the_same_values_it_would_insert() {
+        use chrono::TimeZone;
+
+        let date = Utc.ymd(2020, 1, 2).and_hms(21, 30, 0);
+        let mut escaped = test_row("A\tB\\C\n", Some(7.06));
+        escaped.date = date;
+        escaped.bucket = Some(Bucket::BigGain);
+        let mut halted = test_row("XYZ", None);
+        halted.date = date;
+        halted.is_halted = true;
+        let run_id = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+
+        let storage = PercentageStorage::BasisPoints;
+        let after_market_data = vec![escaped, halted];
+        let columns = Columns::all();
+        let copied = copy_text(&after_market_data, storage, &run_id, &columns);
+        let inserted = insert_statements(&after_market_data, 500, storage, &run_id, &columns);
+        let params = &inserted[0].params;
+
+        let lines: Vec<&str> = copied.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (row, line) in lines.iter().enumerate() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), INSERT_COLUMNS, "{:?}", line);
+            let values: Vec<String> = params[row * INSERT_COLUMNS..(row + 1) * INSERT_COLUMNS]
+                .iter()
+                .map(|p| format!("{:?}", p))
+                .collect();
+            let expected: [(&str, &str); 6] = if row == 0 {
+                [
+                    ("A\\tB\\\\C\\n", "\"A\\tB\\\\C\\n\""),
+                    ("706", "Some(706)"),
+                    ("2020-01-02T21:30:00+00:00", "2020-01-02T21:30:00Z"),
+                    ("false", "false"),
+                    ("big_gain", "Some(\"big_gain\")"),
+                    (
+                        "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                        "\"67e55044-10b1-426f-9247-bb680e5fe0c8\"",
+                    ),
+                ]
+            } else {
+                [
+                    ("XYZ", "\"XYZ\""),
+                    ("\\N", "None"),
+                    ("2020-01-02T21:30:00+00:00", "2020-01-02T21:30:00Z"),
+                    ("true", "true"),
+                    ("\\N", "None"),
+                    (
+                        "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                        "\"67e55044-10b1-426f-9247-bb680e5fe0c8\"",
+                    ),
+                ]
+            };
+            for ((field, value), (want_field, want_value)) in
+                fields.iter().zip(values.iter()).zip(expected.iter())
+            {
+                assert_eq!(field, want_field);
+                assert_eq!(value, want_value);
+            }
+        }
+    }
+
+    #[test]
+    fn every_field_has_a_column() {
+        let row = serde_json::to_value(test_row("ABC", None)).unwrap();
+        let mut fields: Vec<&str> = row
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort();
+        // the run id is the same for every row, so it isn't one of their fields
+        let mut columns: Vec<&str> = COLUMNS
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|name| *name != "run_id")
+            .collect();
+        columns.sort();
+        assert_eq!(fields, columns);
+    }
+
+    #[test]
+    fn the_checked_in_create_table_sql_matches_the_columns() {
+        // the files may open with comments saying what they're for
+        let statements = |sql: &str| -> String {
+            sql.lines()
+                .filter(|line| !line.starts_with("--"))
+                .map(|line| format!("{}\n", line))
+                .collect()
+        };
+        assert_eq!(
+            statements(include_str!("pq/create_after_market_table.sql")),
+            create_table_sql(PercentageStorage::Float, &Columns::all())
+        );
+        assert_eq!(
+            statements(include_str!("pq/create_after_market_bps_table.sql")),
+            create_table_sql(PercentageStorage::BasisPoints, &Columns::all())
+        );
+    }
+
+    #[test]
+    fn an_older_schema_stores_only_its_columns() {
+        let columns = Columns::enabled(&["date", "symbol", "percentage"]).unwrap();
+        assert_eq!(columns.to_string(), "symbol,percentage,date");
+
+        let after_market_data = vec![test_row("ABC", Some(7.06))];
+        let storage = PercentageStorage::Float;
+        let statements =
+            insert_statements(&after_market_data, 500, storage, &Uuid::nil(), &columns);
+        assert_eq!(
+            statements[0].sql,
+            "INSERT INTO after_market (symbol, percentage, date) \
+             VALUES ($1, $2::double precision, $3)"
+        );
+        assert_eq!(statements[0].params.len(), 3);
+        let copied = copy_text(&after_market_data, storage, &Uuid::nil(), &columns);
+        assert_eq!(copied.trim_end().split('\t').count(), 3);
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres database at DATABASE_URL"]
+    fn copy_and_insert_store_the_same_rows() {
+        // run with --release --nocapture, it prints how long each took
+        use chrono::TimeZone;
+        use std::time::Instant;
+
+        let conn = connect().unwrap();
+        // a temporary after_market hides the real one for this session only,
+        // so the benchmark never touches stored rows
+        let storage = PercentageStorage::Float;
+        let columns = Columns::all();
+        conn.batch_execute(
+            &create_table_sql(storage, &columns)
+                .replace("CREATE TABLE IF NOT EXISTS", "CREATE TEMPORARY TABLE"),
+        )
+        .unwrap();
+
+        // Postgres keeps microseconds, so a date with nanoseconds wouldn't
+        // come back the same
+        let date = Utc.ymd(2020, 1, 2).and_hms(21, 30, 0);
+        let mut after_market_data: Vec<AfterMarketPriceData> = (0..20_000)
+            .map(|i| AfterMarketPriceData {
+                date,
+                ..test_row(&format!("T{:05}", i), Some(f64::from(i % 2000) / 100.0))
+            })
+            .collect();
+        after_market_data[1].percentage = None;
+        after_market_data[2].is_halted = true;
+        after_market_data[3].bucket = Some(Bucket::BigGain);
+
+        let mut stored = Vec::new();
+        for mode in [StoreMode::Rows, StoreMode::Copy].iter() {
+            conn.execute(&format!("DELETE FROM {}", TABLE_NAME), &[])
+                .unwrap();
+            let run_id = Uuid::new_v4();
+            let started = Instant::now();
+            match mode {
+                StoreMode::Rows => insert_after_market_data_into_db(
+                    &conn,
+                    &after_market_data,
+                    MAX_INSERT_CHUNK_SIZE as usize,
+                    storage,
+                    &run_id,
+                    &columns,
+                    None,
+                ),
+                _ => copy_after_market_data_into_db(
+                    &conn,
+                    &after_market_data,
+                    storage,
+                    &run_id,
+                    &columns,
+                    None,
+                ),
+            }
+            .unwrap();
+            println!(
+                "{:?}: stored {} rows in {:?}",
+                mode,
+                after_market_data.len(),
+                started.elapsed()
+            );
+
+            let filter = QueryOpts {
+                symbol: None,
+                run_id: Some(run_id),
+                limit: None,
+                since: None,
+                until: None,
+            };
+            stored.push(query_after_market_data(&conn, &filter, storage, &columns).unwrap());
+        }
+
+        let json = |rows: &[AfterMarketPriceData]| serde_json::to_string(rows).unwrap();
+        assert_eq!(json(&stored[0]), json(&after_market_data));
+        assert_eq!(json(&stored[1]), json(&after_market_data));
+    }
+
+    #[test]
+    fn the_required_columns_cant_be_left_out() {
+        assert!(Columns::enabled(&["symbol", "percentage"]).is_err());
+        assert!(Columns::enabled(&["symbol", "percentage", "date", "price"]).is_err());
+        assert!(Columns::enabled(&["symbol", "percentage", "date", "run_id"]).is_ok());
+    }
+}
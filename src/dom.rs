@@ -0,0 +1,706 @@
+use failure::{Fail, Fallible};
+use headless_chrome::browser::tab::element::Element;
+use headless_chrome::browser::Tab;
+use headless_chrome::protocol::dom::{methods::DescribeNode, Node};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::selectors::{TABLE_TAG, TEXT_NODE};
+
+/// The depths we ask Chrome to describe an element's subtree to, in turn,
+/// until the Node we need turns up. The first matches what
+/// `Element::get_description` uses, and -1 asks for the entire subtree.
+const DESCRIBE_DEPTHS: &[i32] = &[100, -1];
+
+/// Describe `element`'s subtree, making sure it's deep enough to contain a
+/// Node for which `wanted` returns true. Heavy pages sometimes give us a
+/// shallower tree than we need, so if the Node is missing we ask again for
+/// the full depth before giving up.
+pub fn describe_containing<F>(element: &Element, what: &str, wanted: F) -> Fallible<Node>
+where
+    F: Fn(&Node) -> bool,
+{
+    for depth in DESCRIBE_DEPTHS.iter() {
+        let node = describe(element, *depth)?;
+        if node.find(|n| wanted(n)).is_some() {
+            return Ok(node);
+        }
+        warn!(
+            "couldn't find {} describing {:?} to depth {}",
+            what, element, depth
+        );
+    }
+
+    Err(MissingNode {
+        what: what.to_string(),
+        element: format!("{:?}", element),
+    }
+    .into())
+}
+
+/// The page came back without a Node we need, even described at full depth.
+/// Unlike a timeout, that usually means the page changed, so retrying won't
+/// help.
+#[derive(Debug)]
+pub struct MissingNode {
+    what: String,
+    element: String,
+}
+
+impl fmt::Display for MissingNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "couldn't find {} in {} even at full depth",
+            self.what, self.element
+        )
+    }
+}
+
+impl Fail for MissingNode {}
+
+/// The page is missing something we always need, like a movers row's
+/// symbol. Like a MissingNode, that means the page has changed, so retrying
+/// won't help.
+#[derive(Debug)]
+pub struct PageChanged(pub String);
+
+impl fmt::Display for PageChanged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Fail for PageChanged {}
+
+/// Describe `element`'s subtree down to `depth` levels, or all of it for -1
+pub fn describe(element: &Element, depth: i32) -> Fallible<Node> {
+    Ok(element
+        .parent
+        .call_method(DescribeNode {
+            node_id: None,
+            backend_node_id: Some(element.backend_node_id),
+            depth: Some(depth),
+        })?
+        .node)
+}
+
+/// Poll the number of children of the element matching `selector` until it
+/// has stayed the same for `stable_rounds` polls in a row, so a table that's
+/// still filling in lazily has finished before we read it. Returns whether it
+/// settled before `timeout`; an element that hasn't appeared yet counts as
+/// unsettled.
+pub fn wait_until_stable(
+    tab: &Tab,
+    selector: &str,
+    poll_interval: Duration,
+    stable_rounds: u32,
+    timeout: Duration,
+) -> Fallible<bool> {
+    let started = Instant::now();
+    let mut last_count = None;
+    let mut unchanged = 0;
+    while started.elapsed() < timeout {
+        let count = match tab.find_element(selector) {
+            Ok(element) => describe(&element, 1)?.child_node_count,
+            Err(_) => None,
+        };
+
+        if count.is_some() && count == last_count {
+            unchanged += 1;
+            if unchanged >= stable_rounds {
+                debug!(
+                    "{} settled at {:?} children after {:?}",
+                    selector,
+                    count,
+                    started.elapsed()
+                );
+                return Ok(true);
+            }
+        } else {
+            unchanged = 0;
+        }
+        last_count = count;
+        thread::sleep(poll_interval);
+    }
+
+    Ok(false)
+}
+
+/// Turn a Node tree back into the JSON DevTools sent us for it, so it can be
+/// saved and deserialized into a Node again later. Only the fields the
+/// scraper looks at are kept, and attributes go back to DevTools' flat
+/// [name, value, name, value, ...] array.
+pub fn node_to_json(node: &Node) -> Value {
+    let mut value = json!({
+        "nodeId": node.node_id,
+        "backendNodeId": node.backend_node_id,
+        "nodeType": node.node_type,
+        "nodeName": node.node_name,
+        "localName": node.local_name,
+        "nodeValue": node.node_value,
+    });
+
+    if let Some(attrs) = &node.attributes {
+        // sort them so dumping the same page twice gives the same file
+        let mut attrs: Vec<_> = attrs.iter().collect();
+        attrs.sort();
+        let flat: Vec<&String> = attrs.into_iter().flat_map(|(k, v)| vec![k, v]).collect();
+        value["attributes"] = json!(flat);
+    }
+    if let Some(count) = node.child_node_count {
+        value["childNodeCount"] = json!(count);
+    }
+    if let Some(children) = &node.children {
+        value["children"] = Value::Array(children.iter().map(node_to_json).collect());
+    }
+
+    value
+}
+
+/// The elements HTML doesn't close, which mustn't get a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Rebuild HTML for a Node tree, close enough to the page's own to see the
+/// structure and text that a selector failed on
+pub fn node_to_html(node: &Node) -> String {
+    let mut html = String::new();
+    write_html(&mut html, node);
+    html
+}
+
+fn write_html(html: &mut String, node: &Node) {
+    match node.node_type {
+        // text
+        3 => html.push_str(&escape_html(&node.node_value)),
+        // comment
+        8 => html.push_str(&format!("<!--{}-->", node.node_value)),
+        // element
+        1 => {
+            html.push('<');
+            html.push_str(&node.local_name);
+            if let Some(attrs) = &node.attributes {
+                let mut attrs: Vec<_> = attrs.iter().collect();
+                attrs.sort();
+                for (name, value) in attrs {
+                    html.push_str(&format!(" {}=\"{}\"", name, escape_html(value)));
+                }
+            }
+            html.push('>');
+            if VOID_ELEMENTS.contains(&node.local_name.as_str()) {
+                return;
+            }
+            write_children(html, node);
+            html.push_str(&format!("</{}>", node.local_name));
+        }
+        // documents, fragments and the like are just their children
+        _ => write_children(html, node),
+    }
+}
+
+fn write_children(html: &mut String, node: &Node) {
+    if let Some(children) = &node.children {
+        for child in children.iter() {
+            write_html(html, child);
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// NodeIndex maps the class attributes and text values found beneath a Node
+/// to the Node that `Node::find` would return for them, so we walk a subtree
+/// once instead of once per lookup
+pub struct NodeIndex<'a> {
+    by_class: HashMap<&'a str, Found<'a>>,
+    by_value: HashMap<&'a str, Found<'a>>,
+}
+
+/// What `Node::find` returns for a key, and the ancestors of the first Node
+/// carrying it
+struct Found<'a> {
+    node: &'a Node,
+    ancestors: Vec<&'a Node>,
+}
+
+impl<'a> NodeIndex<'a> {
+    pub fn new(root: &'a Node) -> NodeIndex<'a> {
+        let mut index = NodeIndex {
+            by_class: HashMap::new(),
+            by_value: HashMap::new(),
+        };
+        let mut path = Vec::new();
+        index.visit(root, &mut path);
+        index
+    }
+
+    fn visit(&mut self, n: &'a Node, path: &mut Vec<&'a Node>) {
+        if let Some(class) = class_of(n) {
+            found(&mut self.by_class, class, n, path);
+        }
+        found(&mut self.by_value, n.node_value.as_str(), n, path);
+
+        if let Some(children) = &n.children {
+            path.push(n);
+            for child in children.iter() {
+                self.visit(child, path);
+            }
+            path.pop();
+        }
+    }
+
+    /// Returns the Node whose class attribute is equal to `s`, as
+    /// `get_node_with_class_as_option` would
+    pub fn class(&self, s: &str) -> Option<&'a Node> {
+        self.by_class.get(s).map(|found| found.node)
+    }
+
+    /// Returns the Node whose value is equal to `s`, as `Node::find` would
+    pub fn value(&self, s: &str) -> Option<&'a Node> {
+        self.by_value.get(s).map(|found| found.node)
+    }
+}
+
+/// Record that `n`, beneath `path`, carries `key`. `Node::find` stops
+/// descending once it has a match, but still tests the later siblings of the
+/// first match and of that match's ancestors, keeping the last one that
+/// matches. So a later Node only replaces the first when its parent is one
+/// of the first's ancestors.
+fn found<'a>(keys: &mut HashMap<&'a str, Found<'a>>, key: &'a str, n: &'a Node, path: &[&'a Node]) {
+    match keys.get_mut(key) {
+        None => {
+            keys.insert(
+                key,
+                Found {
+                    node: n,
+                    ancestors: path.to_vec(),
+                },
+            );
+        }
+        Some(first) => {
+            let depth = path.len();
+            let tested_by_find = depth > 0
+                && first
+                    .ancestors
+                    .get(depth - 1)
+                    .is_some_and(|a| std::ptr::eq(*a, path[depth - 1]));
+            if tested_by_find {
+                first.node = n;
+            }
+        }
+    }
+}
+
+/// Given a Node, search through its HTML looking for another Node with a tag
+/// whose type is equal to `s`. Not finding one means the page has changed.
+pub fn get_node_with_name<'a>(node: &'a Node, s: &str) -> Result<&'a Node, PageChanged> {
+    node.find(|n| n.node_name == s)
+        .ok_or_else(|| PageChanged(format!("couldn't find {:?} tag with node: {:?}", s, node)))
+}
+
+/// Find the innermost table beneath `node` that has a text Node reading
+/// `heading` somewhere inside it, so a table can be located by what it says
+/// rather than by an id that might change
+pub fn find_table_by_heading<'a>(node: &'a Node, heading: &str) -> Option<&'a Node> {
+    if let Some(children) = &node.children {
+        if let Some(table) = children
+            .iter()
+            .find_map(|child| find_table_by_heading(child, heading))
+        {
+            return Some(table);
+        }
+    }
+
+    let has_heading = |n: &Node| n.node_name == TEXT_NODE && n.node_value.trim() == heading;
+    if node.node_name == TABLE_TAG && node.find(has_heading).is_some() {
+        Some(node)
+    } else {
+        None
+    }
+}
+
+/// Given a Node, return the trimmed value of the first text Node beneath it
+/// that isn't just whitespace. Markup often has whitespace-only text Nodes
+/// before the one we actually want. This walks the tree itself rather than
+/// using `Node::find`, which would return the last of several sibling text
+/// Nodes instead of the first.
+pub fn first_nonempty_text(node: &Node) -> Option<String> {
+    if node.node_name == TEXT_NODE && !node.node_value.trim().is_empty() {
+        return Some(node.node_value.trim().to_string());
+    }
+    node.children
+        .as_ref()
+        .and_then(|children| children.iter().find_map(first_nonempty_text))
+}
+
+/// Same as `get_node_with_class_as_option`, but not finding one is an error
+/// saying the page has changed
+pub fn get_node_with_class<'a>(node: &'a Node, s: &str) -> Result<&'a Node, PageChanged> {
+    get_node_with_class_as_option(node, s)
+        .ok_or_else(|| PageChanged(format!("couldn't find {:?}: {:?}", s, node)))
+}
+
+/// Given a Node, search through its HTML looking for another Node with a tag
+/// whose class is equal to 's'
+pub fn get_node_with_class_as_option<'a>(node: &'a Node, s: &str) -> Option<&'a Node> {
+    node.find(|n| class_of(n) == Some(s))
+}
+
+/// Borrow a Node's class attribute, if it has one
+pub fn class_of(node: &Node) -> Option<&str> {
+    node.attributes
+        .as_ref()
+        .and_then(|attrs| attrs.get("class"))
+        .map(String::as_str)
+}
+
+/// Builders for hand-made Node trees, shaped like the ones DevTools sends
+#[cfg(test)]
+pub mod fixtures {
+    use headless_chrome::protocol::dom::Node;
+    use serde_json::{json, Value};
+
+    /// An element with the tag `tag`, the class attribute `class` if there is
+    /// one, and `children`
+    pub fn element(tag: &str, class: Option<&str>, children: Vec<Value>) -> Value {
+        let attributes: Vec<&str> = match class {
+            Some(class) => vec!["class", class],
+            None => vec![],
+        };
+        json!({
+            "nodeId": 0,
+            "backendNodeId": 0,
+            "nodeType": 1,
+            "nodeName": tag.to_uppercase(),
+            "localName": tag,
+            "nodeValue": "",
+            "attributes": attributes,
+            "childNodeCount": children.len(),
+            "children": children,
+        })
+    }
+
+    /// A text Node reading `value`
+    pub fn text(value: &str) -> Value {
+        json!({
+            "nodeId": 0,
+            "backendNodeId": 0,
+            "nodeType": 3,
+            "nodeName": "#text",
+            "localName": "",
+            "nodeValue": value,
+        })
+    }
+
+    pub fn node(value: Value) -> Node {
+        serde_json::from_value(value).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{element, node, text};
+    use super::*;
+
+    /// A movers row: the symbol cell, a name cell and a change cell
+    fn row() -> Node {
+        node(element(
+            "tr",
+            None,
+            vec![
+                element(
+                    "td",
+                    Some("wsod_firstCol"),
+                    vec![element("a", None, vec![text("ABC")])],
+                ),
+                element("td", Some("wsod_name"), vec![text("ABC Corp")]),
+                element(
+                    "td",
+                    Some("posChangePct"),
+                    vec![element("span", None, vec![text("+7.06%")])],
+                ),
+            ],
+        ))
+    }
+
+    #[test]
+    fn index_matches_find_for_a_row() {
+        let row = row();
+        let index = NodeIndex::new(&row);
+        for class in ["wsod_firstCol", "wsod_name", "posChangePct"].iter() {
+            let indexed = index.class(class).unwrap();
+            let found = get_node_with_class_as_option(&row, class).unwrap();
+            assert!(std::ptr::eq(indexed, found), "{} differs", class);
+        }
+        for value in ["ABC", "ABC Corp", "+7.06%"].iter() {
+            let indexed = index.value(value).unwrap();
+            let found = row.find(|n| n.node_value == *value).unwrap();
+            assert!(std::ptr::eq(indexed, found), "{:?} differs", value);
+        }
+        assert!(index.class("negChangePct").is_none());
+        assert!(index.value("-3.99%").is_none());
+        assert_index_matches_find(&row);
+    }
+
+    /// Check the index gives the same Node as `find` for every class and
+    /// value in `tree`
+    fn assert_index_matches_find(tree: &Node) {
+        let index = NodeIndex::new(tree);
+        let mut stack = vec![tree];
+        while let Some(n) = stack.pop() {
+            if let Some(class) = class_of(n) {
+                let found = get_node_with_class_as_option(tree, class).unwrap();
+                assert!(
+                    std::ptr::eq(index.class(class).unwrap(), found),
+                    "class {:?}",
+                    class
+                );
+            }
+            let value = n.node_value.as_str();
+            let found = tree.find(|m| m.node_value == value).unwrap();
+            assert!(
+                std::ptr::eq(index.value(value).unwrap(), found),
+                "value {:?}",
+                value
+            );
+            if let Some(children) = &n.children {
+                stack.extend(children.iter());
+            }
+        }
+    }
+
+    #[test]
+    fn index_keeps_a_later_shallow_match_like_find() {
+        let tree = node(element(
+            "div",
+            None,
+            vec![
+                element(
+                    "p",
+                    None,
+                    vec![element("span", Some("x"), vec![text("deep")])],
+                ),
+                element("span", Some("x"), vec![text("shallow")]),
+            ],
+        ));
+        let index = NodeIndex::new(&tree);
+        assert_eq!(
+            first_nonempty_text(index.class("x").unwrap()).unwrap(),
+            "shallow"
+        );
+        assert_index_matches_find(&tree);
+    }
+
+    #[test]
+    fn index_keeps_the_last_of_several_sibling_matches_like_find() {
+        let tree = node(element(
+            "tr",
+            None,
+            vec![
+                element("td", Some("x"), vec![text("first")]),
+                element("td", Some("x"), vec![text("second")]),
+            ],
+        ));
+        let index = NodeIndex::new(&tree);
+        assert_eq!(
+            first_nonempty_text(index.class("x").unwrap()).unwrap(),
+            "second"
+        );
+        assert_index_matches_find(&tree);
+    }
+
+    #[test]
+    fn index_ignores_a_match_nested_under_a_later_sibling_like_find() {
+        // find doesn't descend into the siblings after its first match, so
+        // the "x" inside the second cell is never tested
+        let tree = node(element(
+            "tr",
+            None,
+            vec![
+                element("td", Some("x"), vec![text("first")]),
+                element(
+                    "td",
+                    None,
+                    vec![element("span", Some("x"), vec![text("nested")])],
+                ),
+                element("td", None, vec![text("first")]),
+            ],
+        ));
+        let index = NodeIndex::new(&tree);
+        assert_eq!(
+            first_nonempty_text(index.class("x").unwrap()).unwrap(),
+            "first"
+        );
+        assert_index_matches_find(&tree);
+    }
+
+    #[test]
+    fn first_nonempty_text_skips_whitespace_only_text() {
+        let cell = node(element(
+            "td",
+            None,
+            vec![
+                text("\n    "),
+                element("span", None, vec![text(" \t ")]),
+                element("a", None, vec![text("  ABC\n")]),
+                text("\n"),
+            ],
+        ));
+        assert_eq!(first_nonempty_text(&cell).unwrap(), "ABC");
+    }
+
+    #[test]
+    fn first_nonempty_text_keeps_the_first_of_several_sibling_texts() {
+        // <td>ABC<br>Inc</td>
+        let name = node(element(
+            "td",
+            None,
+            vec![text("ABC"), element("br", None, vec![]), text("Inc")],
+        ));
+        assert_eq!(first_nonempty_text(&name).unwrap(), "ABC");
+
+        // <td class="posChangePct">+7.06%<br>+0.50</td>
+        let change = node(element(
+            "td",
+            Some("posChangePct"),
+            vec![text("+7.06%"), element("br", None, vec![]), text("+0.50")],
+        ));
+        assert_eq!(first_nonempty_text(&change).unwrap(), "+7.06%");
+    }
+
+    #[test]
+    fn first_nonempty_text_is_none_when_all_text_is_whitespace() {
+        let cell = node(element("td", None, vec![text("\n  "), text(" ")]));
+        assert_eq!(first_nonempty_text(&cell), None);
+    }
+
+    #[test]
+    fn finds_the_innermost_table_under_its_heading() {
+        let page = node(element(
+            "body",
+            None,
+            vec![
+                element(
+                    "table",
+                    Some("other"),
+                    vec![element("th", None, vec![text("Most Active")])],
+                ),
+                // a layout table wrapping the one we want
+                element(
+                    "table",
+                    Some("layout"),
+                    vec![element(
+                        "table",
+                        Some("movers"),
+                        vec![
+                            element("th", None, vec![text("\n  Gainers & Losers ")]),
+                            element("tbody", None, vec![]),
+                        ],
+                    )],
+                ),
+            ],
+        ));
+        let table = find_table_by_heading(&page, "Gainers & Losers").unwrap();
+        assert_eq!(class_of(table), Some("movers"));
+    }
+
+    #[test]
+    fn finds_no_table_without_its_heading() {
+        let page = node(element(
+            "body",
+            None,
+            vec![
+                element(
+                    "table",
+                    None,
+                    vec![element("th", None, vec![text("Most Active")])],
+                ),
+                // the heading outside of any table doesn't count
+                element("h2", None, vec![text("Gainers & Losers")]),
+            ],
+        ));
+        assert!(find_table_by_heading(&page, "Gainers & Losers").is_none());
+    }
+
+    /// Counts the allocations made on the current thread, so a test can see
+    /// how many a piece of code makes without the other tests' counting
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// How many allocations `f` makes
+    fn allocations<T>(f: impl FnOnce() -> T) -> usize {
+        let before = ALLOCATIONS.with(|count| count.get());
+        let result = f();
+        let after = ALLOCATIONS.with(|count| count.get());
+        drop(result);
+        after - before
+    }
+
+    /// The class lookup as it was before SelectorSet, cloning each Node's
+    /// attributes and the class name for every comparison
+    fn get_node_with_class_by_cloning<'a>(node: &'a Node, s: &str) -> Option<&'a Node> {
+        node.find(|n| {
+            let attrs = n.attributes.clone();
+            attrs.unwrap_or_default().get("class") == Some(&s.to_string())
+        })
+    }
+
+    #[test]
+    fn class_lookups_dont_allocate() {
+        // a table of a hundred rows, looking up a class only the last has
+        let mut rows: Vec<Value> = (0..100).map(|_| row_value("wsod_name")).collect();
+        rows.push(row_value("wsod_last"));
+        let table = node(element("tbody", None, rows));
+
+        let cloning = allocations(|| get_node_with_class_by_cloning(&table, "wsod_last"));
+        let borrowing = allocations(|| get_node_with_class_as_option(&table, "wsod_last"));
+        println!(
+            "class lookup over {} rows: {} allocations cloning, {} borrowing",
+            table.children.as_ref().map_or(0, Vec::len),
+            cloning,
+            borrowing
+        );
+        assert!(cloning > 100, "{}", cloning);
+        assert_eq!(borrowing, 0);
+    }
+
+    fn row_value(name_class: &str) -> Value {
+        element(
+            "tr",
+            None,
+            vec![
+                element("td", Some("wsod_firstCol"), vec![text("ABC")]),
+                element("td", Some(name_class), vec![text("ABC Corp")]),
+            ],
+        )
+    }
+}
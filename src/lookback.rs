@@ -0,0 +1,74 @@
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::cli::OutputFormat;
+use crate::output::{csv_field, markdown_cell, write_jsonl_record};
+
+/// LastSeen is how long a symbol had been off the movers before its latest
+/// appearance, for spotting names that keep coming back
+#[derive(Debug, Serialize)]
+pub struct LastSeen {
+    pub symbol: String,
+    /// Trading days between its latest appearance and the one before it, or
+    /// `None` if it's never been a mover before
+    pub days_since_last_seen: Option<i64>,
+}
+
+/// Print the lookback to stdout in the requested format
+pub fn write_last_seen(last_seen: &[LastSeen], format: OutputFormat) -> Result<(), failure::Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match format {
+        OutputFormat::Jsonl => {
+            for l in last_seen.iter() {
+                write_jsonl_record(&mut out, l)?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "symbol,days_since_last_seen")?;
+            for l in last_seen.iter() {
+                writeln!(
+                    out,
+                    "{},{}",
+                    csv_field(&l.symbol),
+                    l.days_since_last_seen
+                        .map(|days| days.to_string())
+                        .unwrap_or_default()
+                )?;
+            }
+        }
+        OutputFormat::Markdown => {
+            writeln!(out, "| Symbol | Trading days since last seen |")?;
+            writeln!(out, "| :--- | ---: |")?;
+            for l in last_seen.iter() {
+                writeln!(
+                    out,
+                    "| {} | {} |",
+                    markdown_cell(&l.symbol),
+                    describe_days(l.days_since_last_seen)
+                )?;
+            }
+        }
+        OutputFormat::Debug => {
+            writeln!(out, "{:<10} {:>12}", "symbol", "last seen")?;
+            for l in last_seen.iter() {
+                writeln!(
+                    out,
+                    "{:<10} {:>12}",
+                    l.symbol,
+                    describe_days(l.days_since_last_seen)
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_days(days: Option<i64>) -> String {
+    match days {
+        Some(days) => days.to_string(),
+        None => "first time".to_string(),
+    }
+}
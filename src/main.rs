@@ -1,206 +1,1751 @@
 use chrono::{DateTime, Utc};
-use core::num::ParseFloatError;
-use failure::Fallible;
+use clap::Parser;
+use failure::{bail, format_err, Fail, Fallible};
+use headless_chrome::browser::default_executable;
+use headless_chrome::browser::tab::element::Element;
 use headless_chrome::browser::Tab;
 use headless_chrome::protocol::dom::Node;
-use headless_chrome::Browser;
+use headless_chrome::{Browser, LaunchOptionsBuilder};
+use log::{debug, error, info, warn, LevelFilter};
+use postgres::Connection;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
-
-use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
-use postgres::{Connection, TlsMode};
-use postgres_openssl::OpenSsl;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod buckets;
+mod calendar;
+mod cli;
+mod compare;
+mod db;
+mod dom;
+mod lookback;
+mod numbers;
+mod output;
+mod secrets;
+mod selectors;
+mod summary;
+mod symbols;
+
+use buckets::Bucket;
+use cli::{
+    Command, CompareOpts, DumpDomOpts, FieldPolicy, IndexConfig, LastSeenOpts, Opts, QueryOpts,
+    ReplayOpts, StoreMode,
+};
+use dom::{
+    class_of, describe_containing, find_table_by_heading, first_nonempty_text, get_node_with_class,
+    get_node_with_name, NodeIndex, PageChanged,
+};
+use lookback::LastSeen;
+use numbers::{explicit_sign, Percentage, PercentageError, PercentageStorage, SignSource};
+use selectors::{SelectorSet, CNN_SELECTORS, TABLE_TAG, TEXT_NODE};
+use summary::{RunStatus, RunSummary};
+use symbols::normalize_symbol;
+
 /// AfterMarketPriceData holds all the data necessary to track the performance
 /// of an after-market-traded stock over time
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct AfterMarketPriceData {
     symbol: String,
-    percentage: f64,
+    /// `None` for a halted ticker the page shows no percentage for
+    percentage: Option<Percentage>,
     date: DateTime<Utc>,
+    is_halted: bool,
+    /// Set from the percentage once it's been rounded, by --bucket-thresholds
+    bucket: Option<Bucket>,
+}
+
+/// The symbol we store the S&P 500 under
+const SP_SYMBOL: &str = "S&P";
+
+impl AfterMarketPriceData {
+    /// Whether this row tracks a market index rather than a single stock:
+    /// the S&P, or one of the `indices` configured with --index
+    pub fn is_index(&self, indices: &[IndexConfig]) -> bool {
+        self.symbol == SP_SYMBOL || indices.iter().any(|index| index.label == self.symbol)
+    }
+}
+
+/// A mover stored now, for tests that need rows to work on
+#[cfg(test)]
+fn test_row(symbol: &str, percentage: Option<f64>) -> AfterMarketPriceData {
+    AfterMarketPriceData {
+        symbol: symbol.to_string(),
+        percentage: percentage.map(|p| Percentage::new(p).unwrap()),
+        date: NOW.unwrap(),
+        is_halted: false,
+        bucket: None,
+    }
 }
 
-const TABLE_NAME: &str = "after_market";
+/// The "accept" button on the cookie-consent dialog CNN shows fresh sessions
+const CONSENT_ACCEPT_BUTTON: &str = "button#onetrust-accept-btn-handler";
+
+/// How long we'll wait for the consent dialog before assuming it isn't shown
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Fragments of (lowercased) page titles that mean we got an error or
+/// interstitial page instead of the movers
+const ERROR_PAGE_TITLES: &[&str] = &[
+    "temporarily unavailable",
+    "service unavailable",
+    "bad gateway",
+    "gateway timeout",
+    "internal server error",
+    "page not found",
+];
+
+/// How long to wait before re-running a failed scrape, doubled after every
+/// failed attempt
+const SCRAPE_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The port Chrome's DevTools listen on when we keep the browser around for
+/// inspection with --keep-browser-on-error
+const DEVTOOLS_PORT: u16 = 9222;
+
+/// How long to linger on the site's front page with --warm-up before going
+/// on to the movers page
+const WARM_UP_PAUSE: Duration = Duration::from_secs(2);
 
 lazy_static! {
     static ref NOW: Option<DateTime<Utc>> = Some(Utc::now());
+    /// Tags this run's log lines and stored rows, so they can be matched up
+    static ref RUN_ID: Uuid = Uuid::new_v4();
 }
 
 fn main() {
-    env_logger::init();
-    println!("{:?}", scrape_cnn_after_market_datasource().unwrap());
+    let opts = Opts::parse();
+    init_logging(&opts);
+
+    match &opts.command {
+        Some(Command::Query(query_opts)) => run_query(&opts, query_opts).unwrap(),
+        Some(Command::Compare(compare_opts)) => run_compare(&opts, compare_opts).unwrap(),
+        Some(Command::DumpDom(dump_opts)) => run_dump_dom(&opts, dump_opts).unwrap(),
+        Some(Command::Replay(replay_opts)) => run_replay(&opts, replay_opts).unwrap(),
+        Some(Command::LastSeen(last_seen_opts)) => run_last_seen(&opts, last_seen_opts).unwrap(),
+        Some(Command::Schema) => print!(
+            "{}",
+            db::create_table_sql(opts.percentage_storage, &opts.db_columns)
+        ),
+        None => {
+            let started = Instant::now();
+            let result = run_scrape(&opts);
+            if let Some(path) = &opts.status_file {
+                write_status(path, started.elapsed(), &result);
+            }
+            result.unwrap();
+        }
+    }
+}
+
+/// Overwrite --status-file with how the run went. A status file we can't
+/// write is logged rather than failing a run that otherwise worked.
+fn write_status(path: &Path, duration: Duration, result: &Result<usize, failure::Error>) {
+    let status = RunStatus::new(RUN_ID.to_string(), NOW.unwrap(), duration, result);
+    let written = output::write_atomically(path, |file| {
+        serde_json::to_writer(&mut *file, &status)?;
+        writeln!(file)?;
+        Ok(())
+    });
+    if let Err(e) = written {
+        error!("couldn't write the status file {:?}: {}", path, e);
+    }
+}
+
+/// Set up env_logger from RUST_LOG, letting --quiet and --verbose override it.
+/// Every line carries the run id, so one run's lines can be picked out of a
+/// log several runs wrote to.
+fn init_logging(opts: &Opts) {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.format(|buf, record| {
+        writeln!(
+            buf,
+            "[{} {:<5} {} run={}] {}",
+            buf.timestamp(),
+            record.level(),
+            record.target(),
+            *RUN_ID,
+            record.args()
+        )
+    });
+    if opts.quiet {
+        builder.filter_level(LevelFilter::Error);
+    } else if opts.verbose > 0 {
+        // only turn up our own logging, headless_chrome's debug output is
+        // far too chatty to be useful here
+        let level = match opts.verbose {
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        builder.filter_module(module_path!(), level);
+    }
+    builder.init();
+}
+
+/// Scrape, store and print a run, returning how many rows it scraped
+fn run_scrape(opts: &Opts) -> Result<usize, failure::Error> {
+    info!("starting run {}", *RUN_ID);
+    if opts.skip_non_trading_days {
+        let today = calendar::trading_date(&NOW.unwrap());
+        if !calendar::is_trading_day(today, &opts.holidays) {
+            info!("{} isn't a trading day, skipping the scrape", today);
+            return Ok(0);
+        }
+    }
+
+    // held until the run returns, when dropping the connection releases it.
+    // It's taken before the --once-per-day check, so another instance can't
+    // store a run between our check and our taking the lock.
+    let day_lock = if opts.lock_trading_day {
+        let today = calendar::trading_date(&NOW.unwrap());
+        let conn = db::connect()?;
+        if !db::try_lock_trading_day(&conn, today)? {
+            info!(
+                "another instance holds the lock for {}, skipping the scrape",
+                today
+            );
+            return Ok(0);
+        }
+        Some(conn)
+    } else {
+        None
+    };
+
+    if opts.once_per_day {
+        let today = calendar::trading_date(&NOW.unwrap());
+        let (since, until) = calendar::trading_day_bounds(today);
+        let fresh;
+        let conn = match &day_lock {
+            Some(conn) => conn,
+            None => {
+                fresh = db::connect()?;
+                &fresh
+            }
+        };
+        if db::stored_between(conn, opts.store_mode, &since, &until)? {
+            info!("already stored a run for {}, skipping the scrape", today);
+            return Ok(0);
+        }
+    }
+
+    // there's no point spending minutes scraping if we then can't store the
+    // results, so when the database is required check it's up before we start
+    let preflight_conn = if opts.require_db && !opts.dry_run {
+        let conn = db::connect()?;
+        db::ping_db(&conn)?;
+        Some(conn)
+    } else {
+        None
+    };
+
+    let mut after_market_data = scrape_with_retries(opts)?;
+    finish_rows(opts, &mut after_market_data);
+
+    let summary = RunSummary::new(NOW.unwrap(), &after_market_data, &opts.indices);
+    info!("{}", summary);
+
+    if let Some(fraction) = opts.misparse_fraction {
+        if let Some(warning) =
+            summary::misparse_warning(&after_market_data, fraction, &opts.indices)
+        {
+            if opts.fail_on_misparse {
+                bail!("this looks like a misparse: {}", warning);
+            }
+            warn!("this looks like a misparse: {}", warning);
+        }
+    }
+
+    if let Some(min_rows) = opts.min_rows {
+        let movers = after_market_data
+            .iter()
+            .filter(|d| !d.is_index(&opts.indices))
+            .count();
+        info!("scraped {} movers, needing at least {}", movers, min_rows);
+        if movers < min_rows {
+            bail!(
+                "only scraped {} movers, fewer than --min-rows {}, so not storing them",
+                movers,
+                min_rows
+            );
+        }
+    }
+
+    if opts.dry_run {
+        info!("dry run, not storing {} rows", after_market_data.len());
+    } else {
+        let conn = match preflight_conn {
+            Some(conn) => conn,
+            None => db::connect()?,
+        };
+        store_with_reconnects(conn, opts, &after_market_data, &summary)?;
+    }
+
+    write_results(opts, &after_market_data)?;
+    Ok(after_market_data.len())
+}
+
+/// Round and bucket freshly parsed rows, and put them in a stable order
+fn finish_rows(opts: &Opts, after_market_data: &mut [AfterMarketPriceData]) {
+    for d in after_market_data.iter_mut() {
+        d.percentage = d.percentage.map(|p| p.rounded(opts.precision));
+        d.bucket = d
+            .percentage
+            .map(|p| buckets::percentage_bucket(p, &opts.bucket_thresholds));
+    }
+    // the order rows come out of the DOM in is incidental, so sort them to
+    // make repeated runs over the same data produce identical output
+    after_market_data.sort_by(|a, b| a.symbol.cmp(&b.symbol).then(a.date.cmp(&b.date)));
+}
+
+/// Run the movers parsing over a node tree saved by dump-dom and print what
+/// it extracts, without a browser or the database
+fn run_replay(opts: &Opts, replay_opts: &ReplayOpts) -> Result<(), failure::Error> {
+    let fixture = std::fs::read_to_string(&replay_opts.fixture)?;
+    let node: Node = serde_json::from_str(&fixture)?;
+
+    let mut after_market_data = Vec::new();
+    parse_movers(&node, &CNN_SELECTORS, opts, |price_data| {
+        after_market_data.push(price_data)
+    })?;
+    finish_rows(opts, &mut after_market_data);
+    info!(
+        "{}",
+        RunSummary::new(NOW.unwrap(), &after_market_data, &opts.indices)
+    );
+
+    write_results(opts, &after_market_data)
+}
+
+/// Store the run, reconnecting up to --db-reconnects times if the connection
+/// has been dropped. The preflight connection sits idle through the whole
+/// scrape, which is long enough for some servers to close it.
+fn store_with_reconnects(
+    conn: Connection,
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+    summary: &RunSummary,
+) -> Result<(), failure::Error> {
+    let mut db = Reconnecting::new(conn, opts.db_reconnects, db::connect);
+    store_run(&mut db, opts, after_market_data, summary)
+}
+
+/// A connection that's swapped for a fresh one from `reconnect` when it turns
+/// out to have been dropped, up to `limit` times in all
+struct Reconnecting<C, R> {
+    conn: C,
+    reconnect: R,
+    reconnects: u32,
+    limit: u32,
+}
+
+impl<C, R: FnMut() -> Fallible<C>> Reconnecting<C, R> {
+    fn new(conn: C, limit: u32, reconnect: R) -> Reconnecting<C, R> {
+        Reconnecting {
+            conn,
+            reconnect,
+            reconnects: 0,
+            limit,
+        }
+    }
+
+    /// Run `step`, and run it again on a fresh connection whenever it fails
+    /// because the connection was lost. Only `step` is run again, so steps
+    /// that finished before it, like a committed insert, aren't repeated.
+    fn run<T, F>(&mut self, what: &str, mut step: F) -> Fallible<T>
+    where
+        F: FnMut(&C) -> Fallible<T>,
+    {
+        loop {
+            match step(&self.conn) {
+                Err(e) if self.reconnects < self.limit && is_connection_lost(&e) => {
+                    self.reconnects += 1;
+                    warn!(
+                        "lost the database connection {}, reconnecting ({} of {}): {}",
+                        what, self.reconnects, self.limit, e
+                    );
+                    self.conn = (self.reconnect)()?;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_connection_lost(e: &failure::Error) -> bool {
+    e.downcast_ref::<postgres::Error>()
+        .is_some_and(db::is_connection_lost)
+}
+
+/// Hand the data to whichever of the file and stdout the options ask for
+fn write_results(
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+) -> Result<(), failure::Error> {
+    if let Some(path) = &opts.out_file {
+        output::write_output_file(
+            path,
+            after_market_data,
+            opts.output,
+            opts.display_tz,
+            &opts.csv_columns,
+            &opts.indices,
+        )?;
+    }
+    if !opts.quiet {
+        output::write_output(
+            after_market_data,
+            opts.output,
+            opts.display_tz,
+            &opts.csv_columns,
+            &opts.indices,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write a scrape's results to the database the way the options ask for. Each
+/// step is run again by itself if it loses the connection, so a drop while
+/// verifying doesn't insert the already committed rows a second time.
+fn store_run<R>(
+    db: &mut Reconnecting<Connection, R>,
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+    summary: &RunSummary,
+) -> Result<(), failure::Error>
+where
+    R: FnMut() -> Fallible<Connection>,
+{
+    let after_market_data = db.run("reading the stored rows", |conn| {
+        rows_to_store(conn, opts, after_market_data)
+    })?;
+
+    // the summary is committed along with the rows, so a run never has one
+    // without the other
+    let summary = if opts.store_summary {
+        Some(summary)
+    } else {
+        None
+    };
+    db.run("storing the run", |conn| {
+        write_run(conn, opts, &after_market_data, summary)
+    })?;
+
+    if opts.verify && opts.store_mode != StoreMode::Snapshot {
+        db.run("verifying the run", |conn| {
+            db::verify_after_market_data(
+                conn,
+                &after_market_data,
+                &NOW.unwrap(),
+                opts.percentage_storage,
+            )
+        })?;
+    }
+
+    Ok(())
 }
 
-pub fn scrape_cnn_after_market_datasource() -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+/// The rows to store once --skip-unchanged and --check-column-scale have
+/// been applied, either of which reads what's already in the database
+fn rows_to_store<'a>(
+    conn: &Connection,
+    opts: &Opts,
+    after_market_data: &'a [AfterMarketPriceData],
+) -> Result<Cow<'a, [AfterMarketPriceData]>, failure::Error> {
+    let mut rows = Cow::Borrowed(after_market_data);
+    if opts.store_mode == StoreMode::Snapshot {
+        return Ok(rows);
+    }
+    if let Some(epsilon) = opts.skip_unchanged {
+        rows = Cow::Owned(changed_rows(conn, opts, &rows, epsilon)?);
+    }
+    if opts.check_column_scale {
+        rows = Cow::Owned(fit_to_percentage_column(conn, opts, &rows)?);
+    }
+
+    Ok(rows)
+}
+
+/// Store the rows, and `summary` if there is one, in a single transaction
+fn write_run(
+    conn: &Connection,
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+    summary: Option<&RunSummary>,
+) -> Result<(), failure::Error> {
+    match opts.store_mode {
+        StoreMode::Rows => db::insert_after_market_data_into_db(
+            conn,
+            after_market_data,
+            opts.insert_chunk_size as usize,
+            opts.percentage_storage,
+            &RUN_ID,
+            &opts.db_columns,
+            summary,
+        )?,
+        StoreMode::Copy => db::copy_after_market_data_into_db(
+            conn,
+            after_market_data,
+            opts.percentage_storage,
+            &RUN_ID,
+            &opts.db_columns,
+            summary,
+        )?,
+        StoreMode::Snapshot => db::store_snapshot(conn, after_market_data, &NOW.unwrap(), summary)?,
+    }
+
+    Ok(())
+}
+
+/// Round the rows' percentages to the scale of the after_market.percentage
+/// column, if it's a NUMERIC, so what we store (and verify) is exactly what
+/// Postgres keeps, and fail on any percentage too big for the column rather
+/// than letting the insert fail or distort it
+fn fit_to_percentage_column(
+    conn: &Connection,
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let (precision, scale) = match db::percentage_column_numeric(conn)? {
+        Some(numeric) => numeric,
+        None => {
+            debug!("the percentage column isn't a NUMERIC with a scale, storing as is");
+            return Ok(after_market_data.to_vec());
+        }
+    };
+    // a NUMERIC(p, s) holds values below 10^(p - s) in magnitude
+    let limit = 10f64.powi(precision - scale);
+
+    let mut fitted = after_market_data.to_vec();
+    for d in fitted.iter_mut() {
+        let p = match d.percentage {
+            Some(p) => p,
+            None => continue,
+        };
+        let stored = match opts.percentage_storage {
+            PercentageStorage::Float => {
+                let rounded = p.rounded(scale.max(0) as u32);
+                d.percentage = Some(rounded);
+                d.bucket = Some(buckets::percentage_bucket(rounded, &opts.bucket_thresholds));
+                rounded.abs().value()
+            }
+            PercentageStorage::BasisPoints => f64::from(p.basis_points().abs()),
+        };
+        if stored >= limit {
+            bail!(
+                "{}'s percentage {} won't fit the percentage column's NUMERIC({}, {})",
+                d.symbol,
+                p,
+                precision,
+                scale
+            );
+        }
+    }
+
+    Ok(fitted)
+}
+
+/// The rows whose percentage moved by more than `epsilon` since the last one
+/// stored for the same symbol on today's trading day, or that haven't been
+/// stored today at all
+fn changed_rows(
+    conn: &Connection,
+    opts: &Opts,
+    after_market_data: &[AfterMarketPriceData],
+    epsilon: f64,
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let (since, until) = calendar::trading_day_bounds(calendar::trading_date(&NOW.unwrap()));
+    let query_opts = QueryOpts {
+        symbol: None,
+        run_id: None,
+        limit: None,
+        since: Some(since),
+        until: Some(until),
+    };
+    // newest first, so the first row we see for a symbol is its latest
+    let mut latest: HashMap<String, Option<Percentage>> = HashMap::new();
+    for d in
+        db::query_after_market_data(conn, &query_opts, opts.percentage_storage, &opts.db_columns)?
+    {
+        latest.entry(d.symbol).or_insert(d.percentage);
+    }
+
+    let changed: Vec<AfterMarketPriceData> = after_market_data
+        .iter()
+        .filter(|d| match (latest.get(&d.symbol), d.percentage) {
+            (None, _) => true,
+            (Some(Some(before)), Some(now)) => (now.value() - before.value()).abs() > epsilon,
+            (Some(before), now) => before.is_some() != now.is_some(),
+        })
+        .cloned()
+        .collect();
+    info!(
+        "skipping {} rows unchanged since their last run today, storing {}",
+        after_market_data.len() - changed.len(),
+        changed.len()
+    );
+
+    Ok(changed)
+}
+
+fn run_query(opts: &Opts, query_opts: &QueryOpts) -> Result<(), failure::Error> {
+    if let (Some(since), Some(until)) = (query_opts.since, query_opts.until) {
+        if since > until {
+            bail!("--since {} is after --until {}", since, until);
+        }
+    }
+
+    let conn = db::connect()?;
+    let after_market_data =
+        db::query_after_market_data(&conn, query_opts, opts.percentage_storage, &opts.db_columns)?;
+
+    write_results(opts, &after_market_data)
+}
+
+fn run_last_seen(opts: &Opts, last_seen_opts: &LastSeenOpts) -> Result<(), failure::Error> {
+    let conn = db::connect()?;
+    let symbols = if last_seen_opts.symbols.is_empty() {
+        db::latest_symbols(&conn)?
+    } else {
+        last_seen_opts.symbols.clone()
+    };
+
+    let mut last_seen = Vec::with_capacity(symbols.len());
+    for symbol in symbols.into_iter() {
+        let days_since_last_seen = db::days_since_last_seen(&conn, &symbol)?;
+        last_seen.push(LastSeen {
+            symbol,
+            days_since_last_seen,
+        });
+    }
+    if opts.quiet {
+        return Ok(());
+    }
+    lookback::write_last_seen(&last_seen, opts.output)
+}
+
+fn run_compare(opts: &Opts, compare_opts: &CompareOpts) -> Result<(), failure::Error> {
+    let conn = db::connect()?;
+    let query_day = |day| {
+        let (since, until) = calendar::trading_day_bounds(day);
+        let query_opts = QueryOpts {
+            symbol: None,
+            run_id: None,
+            limit: None,
+            since: Some(since),
+            until: Some(until),
+        };
+        db::query_after_market_data(
+            &conn,
+            &query_opts,
+            opts.percentage_storage,
+            &opts.db_columns,
+        )
+    };
+    let day_a = query_day(compare_opts.day_a)?;
+    let day_b = query_day(compare_opts.day_b)?;
+
+    let comparisons = compare::compare_days(&day_a, &day_b);
+    if opts.quiet {
+        return Ok(());
+    }
+    compare::write_comparison(
+        &comparisons,
+        compare_opts.day_a,
+        compare_opts.day_b,
+        opts.output,
+    )
+}
+
+/// Run the whole scrape, relaunching the browser and starting over up to
+/// `--scrape-retries` more times (with backoff) if it fails
+fn scrape_with_retries(opts: &Opts) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let attempts = opts.scrape_retries + 1;
+    let mut backoff = SCRAPE_RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        info!("starting scrape attempt {} of {}", attempt, attempts);
+        // only the last attempt's browser is worth keeping, earlier ones
+        // would just be in the way of the retries
+        let keep_browser_on_error = opts.keep_browser_on_error && attempt == attempts;
+        match scrape_cnn_after_market_datasource(opts, keep_browser_on_error) {
+            Ok(after_market_data) => {
+                info!("scrape attempt {} of {} succeeded", attempt, attempts);
+                return Ok(after_market_data);
+            }
+            Err(e) if !is_transient(&e) => {
+                error!(
+                    "scrape attempt {} of {} failed in a way retrying won't fix: {}",
+                    attempt, attempts, e
+                );
+                return Err(e);
+            }
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "scrape attempt {} of {} failed, retrying in {:?}: {}",
+                    attempt, attempts, backoff, e
+                );
+                thread::sleep(backoff);
+                attempt += 1;
+                backoff *= 2;
+            }
+            Err(e) => {
+                error!("scrape failed after {} attempts: {}", attempts, e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Extracting and parsing the loaded page took longer than --parse-timeout
+#[derive(Debug)]
+struct ParseTimeout {
+    elapsed: Duration,
+    limit: Duration,
+}
+
+impl fmt::Display for ParseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "parsing the page took {:?}, more than the {:?} allowed",
+            self.elapsed, self.limit
+        )
+    }
+}
+
+impl Fail for ParseTimeout {}
+
+/// Times extracting and parsing the page against --parse-timeout
+pub struct ParseClock {
+    started: Instant,
+    limit: Duration,
+}
+
+impl ParseClock {
+    fn start(opts: &Opts) -> ParseClock {
+        ParseClock {
+            started: Instant::now(),
+            limit: Duration::from_secs(opts.parse_timeout),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Fail if the parse has run past --parse-timeout
+    fn check(&self) -> Result<(), ParseTimeout> {
+        let elapsed = self.elapsed();
+        if elapsed > self.limit {
+            return Err(ParseTimeout {
+                elapsed,
+                limit: self.limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether a failed scrape is worth retrying. Timeouts, network trouble and
+/// error pages tend to clear up on their own, but a Node missing from a fully
+/// described page, a required field missing from a row or a percentage we
+/// can't parse mean the page has changed.
+fn is_transient(e: &failure::Error) -> bool {
+    e.downcast_ref::<dom::MissingNode>().is_none()
+        && e.downcast_ref::<PageChanged>().is_none()
+        && e.downcast_ref::<PercentageError>().is_none()
+}
+
+/// Navigate to the page and save the movers container's whole DOM subtree to
+/// `--out`, so the parsing code can be run against it offline
+fn run_dump_dom(opts: &Opts, dump_opts: &DumpDomOpts) -> Result<(), failure::Error> {
     let browser = Browser::default()?;
-    let tab = initialize_tab(&browser)?;
+    let tab = initialize_tab(&browser, opts)?;
+
+    let container = tab.wait_for_element(CNN_SELECTORS.movers_container)?;
+    let node = dom::describe(&container, -1)?;
+    std::fs::write(
+        &dump_opts.out,
+        serde_json::to_string_pretty(&dom::node_to_json(&node))?,
+    )?;
+    info!("wrote the movers DOM to {:?}", dump_opts.out);
+
+    Ok(())
+}
+
+pub fn scrape_cnn_after_market_datasource(
+    opts: &Opts,
+    keep_browser_on_error: bool,
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let browser = launch_browser(keep_browser_on_error)?;
+
+    let after_market_data = scrape_with_browser(&browser, opts);
+    if after_market_data.is_err() && keep_browser_on_error {
+        eprintln!(
+            "scrape failed, leaving the browser open with DevTools at http://127.0.0.1:{}",
+            DEVTOOLS_PORT
+        );
+        // dropping the Browser kills the Chrome process, so we never drop it
+        std::mem::forget(browser);
+    }
+
+    after_market_data
+}
+
+/// Launch headless Chrome, or a visible Chrome on a known DevTools port when
+/// we want to be able to poke at the page after a failure
+fn launch_browser(inspectable: bool) -> Fallible<Browser> {
+    if !inspectable {
+        return Browser::default();
+    }
+
+    let launch_options = LaunchOptionsBuilder::default()
+        .path(Some(
+            default_executable().map_err(|e| format_err!("{}", e))?,
+        ))
+        .headless(false)
+        .port(Some(DEVTOOLS_PORT))
+        .build()
+        .map_err(|e| format_err!("{}", e))?;
+    Browser::new(launch_options)
+}
+
+fn scrape_with_browser(
+    browser: &Browser,
+    opts: &Opts,
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    let tab = initialize_tab(browser, opts)?;
 
     // we'll use this to gather all of the ticker data we care about
     let after_market_data = Vec::new();
 
-    let after_market_data = get_after_market_ticker_data(after_market_data, &tab)?;
-    let after_market_data = get_standard_and_poors_ticker_data(after_market_data, &tab)?;
-
-    insert_after_market_data_into_db(&after_market_data);
+    let (after_market_data, parse_clock) =
+        get_after_market_ticker_data(after_market_data, &tab, opts)?;
+    parse_clock.check()?;
+    let after_market_data = get_standard_and_poors_ticker_data(after_market_data, &tab, opts)?;
+    parse_clock.check()?;
+    let after_market_data = get_configured_indices(after_market_data, &tab, &opts.indices, opts)?;
+    parse_clock.check()?;
+    debug!("extracted the page's data in {:?}", parse_clock.elapsed());
+    check_required_indices(&after_market_data, &opts.require_indices)?;
 
     Ok(after_market_data)
 }
 
+/// Fail if any of the `--require-indices` labels is missing from the scrape,
+/// logging each one that is
+fn check_required_indices(
+    after_market_data: &[AfterMarketPriceData],
+    required: &[String],
+) -> Fallible<()> {
+    let missing: Vec<&str> = required
+        .iter()
+        .filter(|label| !after_market_data.iter().any(|d| &d.symbol == *label))
+        .map(String::as_str)
+        .collect();
+    for label in missing.iter() {
+        error!("required index {} wasn't captured", label);
+    }
+
+    if !missing.is_empty() {
+        bail!("missing required indices: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
 fn get_after_market_ticker_data(
     mut v: Vec<AfterMarketPriceData>,
     tab: &Arc<Tab>,
-) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
-    // locate the HTML table with the afterhours trading Gainers and Losers
-    let price_changes_table = tab.wait_for_element("div#wsod_marketMoversContainer")?;
+    opts: &Opts,
+) -> Result<(Vec<AfterMarketPriceData>, ParseClock), failure::Error> {
+    let parse_clock = scrape_rows(tab, opts, |price_data| v.push(price_data))?;
+
+    Ok((v, parse_clock))
+}
+
+/// Parse each row of the afterhours Gainers and Losers table, handing every
+/// row to `f` as soon as it's parsed rather than collecting them all first.
+/// Returns the --parse-timeout clock, started once the page was ready, so
+/// the rest of the page can be timed against the same budget.
+pub fn scrape_rows<F: FnMut(AfterMarketPriceData)>(
+    tab: &Arc<Tab>,
+    opts: &Opts,
+    f: F,
+) -> Result<ParseClock, failure::Error> {
+    let selectors = &CNN_SELECTORS;
+    let movers = wait_for_movers(tab, selectors, opts)?;
+
+    // the movers table has loaded, so from here on any slowness is ours,
+    // including the describes of a page too big to describe in one go
+    let parse_clock = ParseClock::start(opts);
+    let movers = describe_movers(&movers, selectors, opts)?;
+    parse_clock.check()?;
+    parse_movers(&movers, selectors, opts, f)?;
+    parse_clock.check()?;
+
+    Ok(parse_clock)
+}
+
+/// Wait for the part of the page holding the afterhours trading Gainers and
+/// Losers table, as laid out by `selectors`, to load
+fn wait_for_movers<'a>(
+    tab: &'a Arc<Tab>,
+    selectors: &SelectorSet,
+    opts: &Opts,
+) -> Fallible<Element<'a>> {
+    // polling finds the rows by the container's selector, which is exactly
+    // what --find-movers-by-heading doesn't trust to be there
+    if opts.stable_rounds > 0 && opts.find_movers_by_heading {
+        warn!("--stable-rounds doesn't work with --find-movers-by-heading, not waiting");
+    } else if opts.stable_rounds > 0 {
+        let rows = format!("{} {}", selectors.movers_container, selectors.movers_body);
+        let settled = dom::wait_until_stable(
+            tab,
+            &rows,
+            Duration::from_millis(opts.stable_poll_ms),
+            opts.stable_rounds,
+            Duration::from_secs(opts.stable_timeout),
+        )?;
+        if !settled {
+            warn!(
+                "{}'s row count didn't settle within {}s, reading it anyway",
+                rows, opts.stable_timeout
+            );
+        }
+    }
 
-    let node = price_changes_table.get_description()?;
-    let table = get_node_with_name(&node, "TBODY");
-    let rows = table.children.as_ref().unwrap();
+    if opts.find_movers_by_heading {
+        Ok(tab.wait_for_element(selectors.page_body)?)
+    } else {
+        Ok(tab.wait_for_element(selectors.movers_container)?)
+    }
+}
+
+/// Describe the part of the page `wait_for_movers` found, deep enough to
+/// reach the movers table's rows
+fn describe_movers(movers: &Element, selectors: &SelectorSet, opts: &Opts) -> Fallible<Node> {
+    if opts.find_movers_by_heading {
+        // ids come and go, but the table still has to say what it is
+        describe_containing(movers, selectors.movers_header, |n| {
+            n.node_name == TEXT_NODE && n.node_value.trim() == selectors.movers_header
+        })
+    } else {
+        describe_containing(movers, selectors.movers_body, |n| {
+            n.node_name == selectors.movers_body
+        })
+    }
+}
+
+/// Parse the rows of the movers table found in `node` (the movers container,
+/// or the page body with --find-movers-by-heading), handing each to `f`
+fn parse_movers<F: FnMut(AfterMarketPriceData)>(
+    node: &Node,
+    selectors: &SelectorSet,
+    opts: &Opts,
+    mut f: F,
+) -> Result<(), failure::Error> {
+    let table = if opts.find_movers_by_heading {
+        find_table_by_heading(node, selectors.movers_header).ok_or_else(|| {
+            PageChanged(format!(
+                "couldn't find a table headed {:?}",
+                selectors.movers_header
+            ))
+        })?
+    } else {
+        node
+    };
+    let table = get_node_with_name(table, selectors.movers_body)?;
+    let rows = table.children.as_ref().ok_or_else(|| {
+        PageChanged(format!(
+            "{:?} wasn't described with its rows: {:?}",
+            selectors.movers_body, table
+        ))
+    })?;
 
     // now that we've located the rows of the Gainers and Losers, we will
     // discard the first row because it is the table header, and then we'll
     // extract the ticker info with positive price changes
     for row in rows.iter() {
-        let maybe_header = row.find(|n| n.node_value == "Gainers & Losers");
-        if maybe_header.is_some() {
-            // this is the header of the table, so we skip it because
-            // it doesn't contain intersting data
-            continue;
-        }
-        // find the column containing the ticker symbol
-        let first_column = get_node_with_class(row, "wsod_firstCol");
-
-        let ticker_symbol = get_node_with_name(first_column, "#text")
-            .node_value
-            .to_string();
-
-        // the data source marks the price change data value with a different
-        // HTML class depending on if it's negative or positive so we check for both
-        let third_column = match get_node_with_class_as_option(row, "negChangePct") {
-            Some(pct) => pct,
-            None => get_node_with_class_as_option(row, "posChangePct")
-                .unwrap_or_else(|| panic!("couldn't find third_column with row: {:?}", row)),
-        };
+        match parse_row(row, selectors, opts) {
+            Ok(Some(price_data)) => f(price_data),
+            Ok(None) => {}
+            Err(e) => {
+                if opts.debug_row_html {
+                    error!("the row that failed to parse: {}", dom::node_to_html(row));
+                }
+                return Err(e);
+            }
+        }
+    }
 
-        // this gives us a String of the form "+7.06%" or "-3.99%"
-        let price_perc_change = get_node_with_name(third_column, "#text")
-            .node_value
-            .to_string();
+    Ok(())
+}
 
-        let price_perc_change = parse_percentage_str(price_perc_change)?;
+/// Parse one row of the movers table, or return `None` for its header row
+fn parse_row(
+    row: &Node,
+    selectors: &SelectorSet,
+    opts: &Opts,
+) -> Fallible<Option<AfterMarketPriceData>> {
+    // walk each row once up front, so the per-column lookups below don't
+    // each have to search the whole row again
+    let index = NodeIndex::new(row);
 
-        let price_data = AfterMarketPriceData {
-            symbol: ticker_symbol,
-            percentage: price_perc_change,
-            date: NOW.unwrap(),
-        };
+    if index.value(selectors.movers_header).is_some() {
+        // this is the header of the table, so we skip it because
+        // it doesn't contain intersting data
+        return Ok(None);
+    }
+    // find the column containing the ticker symbol
+    // without a symbol there's nothing to record the row under, so it's
+    // always required
+    let first_column = index.class(selectors.symbol_column).ok_or_else(|| {
+        PageChanged(format!(
+            "couldn't find {:?}: {:?}",
+            selectors.symbol_column, row
+        ))
+    })?;
 
-        v.push(price_data);
+    let ticker_symbol = first_nonempty_text(first_column).ok_or_else(|| {
+        PageChanged(format!(
+            "couldn't find symbol text with node: {:?}",
+            first_column
+        ))
+    })?;
+
+    // a halted ticker is still a mover we want to record, but the page
+    // may show the halt instead of a percentage
+    let is_halted = row
+        .find(|n| n.node_name == TEXT_NODE && n.node_value.contains(selectors.halted_marker))
+        .is_some();
+    let symbol = normalize_symbol(&ticker_symbol, &opts.symbol_aliases);
+
+    // the data source marks the price change data value with a different
+    // HTML class depending on if it's negative or positive so we check for both
+    let change_column = match index.class(selectors.negative_change) {
+        Some(pct) => Some((pct, selectors.negative_change)),
+        None => index
+            .class(selectors.positive_change)
+            .map(|pct| (pct, selectors.positive_change)),
+    };
+
+    let (percentage, percentage_path) = match change_column {
+        Some((third_column, change_class)) => match first_nonempty_text(third_column) {
+            // this gives us a String of the form "+7.06%" or "-3.99%"
+            Some(price_perc_change) => {
+                let is_loser = change_class == selectors.negative_change;
+
+                let percentage = match Percentage::parse(
+                    &price_perc_change,
+                    opts.decimal_style,
+                    opts.percentage_unit,
+                ) {
+                    Ok(percentage) => Some(signed_percentage(
+                        opts,
+                        &symbol,
+                        &price_perc_change,
+                        percentage,
+                        is_loser,
+                    )?),
+                    Err(PercentageError::Parse(..)) if is_halted => None,
+                    Err(e) => return Err(e.into()),
+                };
+                let path = format!(
+                    "[class={:?}] {:?} (sign from {:?})",
+                    change_class, price_perc_change, opts.sign_source
+                );
+                (percentage, path)
+            }
+            None => missing_percentage(
+                opts,
+                &symbol,
+                is_halted,
+                format!("no text in [class={:?}]", change_class),
+            )?,
+        },
+        None => missing_percentage(
+            opts,
+            &symbol,
+            is_halted,
+            format!(
+                "no [class={:?}] or [class={:?}]",
+                selectors.negative_change, selectors.positive_change
+            ),
+        )?,
+    };
+
+    let price_data = AfterMarketPriceData {
+        symbol,
+        percentage,
+        date: NOW.unwrap(),
+        is_halted,
+        bucket: None,
+    };
+
+    let row_path = if opts.find_movers_by_heading {
+        format!(
+            "{}[heading={:?}] > {}",
+            TABLE_TAG, selectors.movers_header, selectors.movers_body
+        )
+    } else {
+        format!("{} > {}", selectors.movers_container, selectors.movers_body)
+    };
+    explain(
+        opts,
+        &price_data,
+        &[
+            (
+                "symbol",
+                format!(
+                    "{} > [class={:?}] {:?}",
+                    row_path, selectors.symbol_column, ticker_symbol
+                ),
+            ),
+            ("percentage", format!("{} > {}", row_path, percentage_path)),
+        ],
+    );
+
+    Ok(Some(price_data))
+}
+
+/// Decide what to do about a mover whose percentage cell is missing. Halted
+/// movers and `--percentage-policy optional` record it as `None`, otherwise
+/// the row is an error.
+fn missing_percentage(
+    opts: &Opts,
+    symbol: &str,
+    is_halted: bool,
+    why: String,
+) -> Fallible<(Option<Percentage>, String)> {
+    if is_halted {
+        return Ok((None, format!("{}, halted", why)));
     }
+    match opts.percentage_policy {
+        FieldPolicy::Optional => {
+            warn!(
+                "couldn't find {}'s percentage, storing none: {}",
+                symbol, why
+            );
+            Ok((None, why))
+        }
+        FieldPolicy::Required => {
+            Err(PageChanged(format!("couldn't find {}'s percentage: {}", symbol, why)).into())
+        }
+    }
+}
 
-    Ok(v)
+/// Apply --sign-source to a mover's parsed percentage. With `class`, the
+/// cell's class decides the sign, and a text sign that disagrees is logged.
+fn signed_percentage(
+    opts: &Opts,
+    symbol: &str,
+    text: &str,
+    percentage: Percentage,
+    is_loser: bool,
+) -> Result<Percentage, PercentageError> {
+    if opts.sign_source == SignSource::Text {
+        return Ok(percentage);
+    }
+
+    if let Some(text_is_negative) = explicit_sign(text) {
+        if text_is_negative != is_loser && percentage.value() != 0.0 {
+            warn!(
+                "{}'s text {:?} disagrees with its class, treating it as a {}",
+                symbol,
+                text,
+                if is_loser { "loser" } else { "gainer" }
+            );
+        }
+    }
+    if is_loser {
+        Percentage::new(-percentage.abs().value())
+    } else {
+        Ok(percentage.abs())
+    }
 }
 
 fn get_standard_and_poors_ticker_data(
     mut v: Vec<AfterMarketPriceData>,
     tab: &Arc<Tab>,
+    opts: &Opts,
 ) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
     // we also want the S&P price change, because our strategy takes the movement
     // of the S&P 500 into account (if it's largely positive, then we believe the
     // market will have greater liklihood to buy the trending aftermarket trades)
-    let standard_poors_price_change = tab.find_element("div#premkContent1")?;
-    let node = standard_poors_price_change.get_description()?;
+    let selectors = &CNN_SELECTORS;
+    let standard_poors_price_change = tab.find_element(selectors.index_container)?;
+    let node = describe_containing(
+        &standard_poors_price_change,
+        selectors.standard_and_poors_row,
+        |n| class_of(n) == Some(selectors.standard_and_poors_row),
+    )?;
+    if let Some(price_data) = parse_standard_and_poors(&node, selectors, opts)? {
+        v.push(price_data);
+    }
 
-    let sp_row = get_node_with_class(&node, "wsod_futureQuote wsod_futureQuoteFirst");
-    let sp_price_changes = get_node_with_class(sp_row, "wsod_bold wsod_aRight");
+    Ok(v)
+}
 
-    // this will get us a String of the form "-0.71%"
-    let sp_perc_change = sp_price_changes
-        .find(|n| n.node_value.contains("%"))
-        .unwrap()
-        .node_value
-        .clone(); // TODO firgure out how not to be lazy and not clone everything
+/// Parse the S&P's row out of the index quotes in `node`, or `None` if the
+/// row has no percentage
+fn parse_standard_and_poors(
+    node: &Node,
+    selectors: &SelectorSet,
+    opts: &Opts,
+) -> Fallible<Option<AfterMarketPriceData>> {
+    let sp_row = get_node_with_class(node, selectors.standard_and_poors_row)?;
+    let sp_price_changes = get_node_with_class(sp_row, selectors.index_change)?;
 
-    let sp_perc_change = parse_percentage_str(sp_perc_change)?;
+    // this will get us a String of the form "-0.71%". Like the other indices
+    // the S&P is best-effort, so if it's missing we carry on without it
+    // (--require-indices S&P makes that fail the scrape instead)
+    let sp_perc_change = match sp_price_changes.find(|n| n.node_value.contains('%')) {
+        Some(n) => &n.node_value,
+        None => {
+            warn!(
+                "no percentage for S&P in {:?}, skipping it",
+                selectors.index_change
+            );
+            return Ok(None);
+        }
+    };
+
+    let percentage = Percentage::parse(sp_perc_change, opts.decimal_style, opts.percentage_unit)?;
 
     let price_data = AfterMarketPriceData {
-        symbol: "S&P".to_string(),
-        percentage: sp_perc_change,
+        symbol: SP_SYMBOL.to_string(),
+        percentage: Some(percentage),
         date: NOW.unwrap(),
+        is_halted: false,
+        bucket: None,
     };
-    v.push(price_data);
-
-    Ok(v)
-}
+    explain(
+        opts,
+        &price_data,
+        &[(
+            "percentage",
+            format!(
+                "{} > [class={:?}] > [class={:?}] {:?}",
+                selectors.index_container,
+                selectors.standard_and_poors_row,
+                selectors.index_change,
+                sp_perc_change
+            ),
+        )],
+    );
 
-/// Given a Node, search through its HTML looking for another Node with a tag
-/// whose type is equal to `s`
-fn get_node_with_name<'a>(node: &'a Node, s: &str) -> &'a Node {
-    node.find(|n| n.node_name == s)
-        .unwrap_or_else(|| panic!("couldn't find {:?} tag with node: {:?}", s, node))
+    Ok(Some(price_data))
 }
 
-/// Same as `get_node_with_class_as_option` but unwraps the Option and panics
-/// if it is `None`
-fn get_node_with_class<'a>(node: &'a Node, s: &str) -> &'a Node {
-    match get_node_with_class_as_option(node, s) {
-        Some(n) => n,
-        _ => panic!("couldn't find {:?}: {:?}", s, node),
+/// Scrape each of the `--index` entries, pushing its percentage change under
+/// its label. These indices are nice-to-haves, so one missing from the page
+/// is skipped with a warning rather than failing the whole scrape.
+fn get_configured_indices(
+    mut v: Vec<AfterMarketPriceData>,
+    tab: &Arc<Tab>,
+    indices: &[IndexConfig],
+    opts: &Opts,
+) -> Result<Vec<AfterMarketPriceData>, failure::Error> {
+    for index in indices.iter() {
+        let index_price_change = match tab.find_element(&index.selector) {
+            Ok(element) => element,
+            Err(e) => {
+                warn!(
+                    "couldn't find {} at {:?}, skipping it: {}",
+                    index.label, index.selector, e
+                );
+                continue;
+            }
+        };
+        let node = match index_price_change.get_description() {
+            Ok(node) => node,
+            Err(e) => {
+                warn!(
+                    "couldn't describe {} at {:?}, skipping it: {}",
+                    index.label, index.selector, e
+                );
+                continue;
+            }
+        };
+        if let Some(price_data) = parse_index(index, &node, opts) {
+            v.push(price_data);
+        }
     }
+
+    Ok(v)
 }
 
-/// Given a Node, search through its HTML looking for another Node with a tag
-/// whose class is equal to 's'
-fn get_node_with_class_as_option<'a>(node: &'a Node, s: &str) -> Option<&'a Node> {
-    node.find(|n| {
-        let attrs = n.attributes.clone(); // TODO learn why compiler won't let me because
-                                          // n.attributes so I don't have to use the slower `.clone`
-        attrs.unwrap_or_default().get("class") == Some(&s.to_string())
-    })
+/// Parse the percentage change of the `--index` entry `index` out of `node`,
+/// or warn and return `None` if it has none we can read
+fn parse_index(index: &IndexConfig, node: &Node, opts: &Opts) -> Option<AfterMarketPriceData> {
+    // this will get us a String of the form "+4.12%"
+    let text = match node.find(|n| n.node_value.contains('%')) {
+        Some(n) => &n.node_value,
+        None => {
+            warn!(
+                "no percentage for {} in {:?}, skipping it",
+                index.label, index.selector
+            );
+            return None;
+        }
+    };
+    let index_perc_change = match Percentage::parse(text, opts.decimal_style, opts.percentage_unit)
+    {
+        Ok(percentage) => percentage,
+        Err(e) => {
+            warn!(
+                "couldn't read {}'s percentage in {:?}, skipping it: {}",
+                index.label, index.selector, e
+            );
+            return None;
+        }
+    };
+
+    let price_data = AfterMarketPriceData {
+        symbol: index.label.clone(),
+        percentage: Some(index_perc_change),
+        date: NOW.unwrap(),
+        is_halted: false,
+        bucket: None,
+    };
+    explain(
+        opts,
+        &price_data,
+        &[(
+            "percentage",
+            format!("{} > {} {:?}", index.selector, node.node_name, text),
+        )],
+    );
+    Some(price_data)
 }
 
-/// Strip away the % char so "+7.06%": String becomes 7.06: f64
-fn parse_percentage_str(mut price_change: String) -> Result<f64, ParseFloatError> {
-    price_change.remove(price_change.len() - 1);
-    price_change.parse::<f64>()
+/// With --explain, print to stderr the selector path and raw text that
+/// produced each of a row's fields, so we can see which one broke when the
+/// page changes
+fn explain(opts: &Opts, data: &AfterMarketPriceData, fields: &[(&str, String)]) {
+    if !opts.explain {
+        return;
+    }
+    eprintln!("{}:", data.symbol);
+    for (field, path) in fields.iter() {
+        eprintln!("  {} <- {}", field, path);
+    }
 }
 
-fn initialize_tab(browser: &Browser) -> Fallible<Arc<Tab>> {
+fn initialize_tab(browser: &Browser, opts: &Opts) -> Fallible<Arc<Tab>> {
     let tab = browser.wait_for_initial_tab()?;
 
+    if let Some(source_file) = &opts.source_file {
+        // a saved page has no site to warm up, error pages or consent
+        // dialog, so just load it
+        info!("loading the page from {}", source_file);
+        tab.navigate_to(source_file)?.wait_until_navigated()?;
+        return Ok(tab);
+    }
+
     // navigate to the after hours info webpage
     let after_market_url = match env::var("AFTER_MARKET_URL") {
         Ok(url) => url,
-        Err(error) => panic!("AFTER_MARKET_URL error: {:?}", error),
+        Err(error) => bail!("AFTER_MARKET_URL error: {:?}", error),
     };
-    tab.navigate_to(&after_market_url)?;
+    if opts.warm_up {
+        // a cold session sometimes gets a slimmer movers page, so visit the
+        // front page first to pick up whatever cookies make it a warm one
+        let root = site_root(&after_market_url);
+        info!("warming up the session at {}", root);
+        tab.navigate_to(root)?.wait_until_navigated()?;
+        thread::sleep(WARM_UP_PAUSE);
+    }
+    tab.navigate_to(&after_market_url)?.wait_until_navigated()?;
+    check_for_error_page(&tab)?;
+    dismiss_consent(&tab);
 
     Ok(tab)
 }
 
-fn insert_after_market_data_into_db(after_market_data: &Vec<AfterMarketPriceData>) {
-    // Create Ssl postgres connector without verification as required to connect to Heroku.
-    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
-    builder.set_verify(SslVerifyMode::NONE);
-    let mut connect = OpenSsl::from(builder.build());
-    connect.danger_disable_hostname_verification(true);
-    let conn = Connection::connect(
-        env::var("DATABASE_URL").expect("no env var DATABASE_URL"),
-        TlsMode::Require(&connect),
-    )
-    .unwrap();
+/// The scheme and host of `url`, e.g. "https://money.cnn.com" for
+/// "https://money.cnn.com/data/afterhours/"
+fn site_root(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    match url[host_start..].find('/') {
+        Some(i) => &url[..host_start + i],
+        None => url,
+    }
+}
 
-    for d in after_market_data.iter() {
-        conn.execute(
-            &format!(
-                "INSERT INTO {} (symbol, percentage, date) VALUES ($1, $2, $3)",
-                TABLE_NAME
-            ),
-            &[&d.symbol, &d.percentage, &d.date],
-        )
+/// Fail (so the scrape gets retried) if CNN served us an error or "try again
+/// later" page instead of the movers, rather than going on to parse it and
+/// reporting a confusing missing-element error
+fn check_for_error_page(tab: &Arc<Tab>) -> Fallible<()> {
+    let title = tab.get_title()?;
+    let lowercase_title = title.to_lowercase();
+    if ERROR_PAGE_TITLES
+        .iter()
+        .any(|marker| lowercase_title.contains(marker))
+    {
+        error!(
+            "got an error page titled {:?} from {}",
+            title,
+            tab.get_url()
+        );
+        bail!("the page is an error page: {:?}", title);
+    }
+
+    Ok(())
+}
+
+/// Click away the cookie-consent dialog if it shows up, since it covers the
+/// movers table. Most of the time it isn't there at all, so not finding it
+/// (or failing to click it) is never an error.
+fn dismiss_consent(tab: &Arc<Tab>) {
+    let button =
+        match tab.wait_for_element_with_custom_timeout(CONSENT_ACCEPT_BUTTON, CONSENT_TIMEOUT) {
+            Ok(button) => button,
+            Err(_) => {
+                debug!("no consent dialog found, carrying on");
+                return;
+            }
+        };
+
+    match button.click() {
+        Ok(_) => info!("dismissed the consent dialog"),
+        Err(e) => warn!("found the consent dialog but couldn't dismiss it: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dom::fixtures::{element, node, text};
+    use serde_json::Value;
+
+    fn opts() -> Opts {
+        Opts::parse_from(["strat"])
+    }
+
+    /// A movers row for `symbol`, with `cells` after its symbol cell
+    fn row(symbol: &str, cells: Vec<Value>) -> Node {
+        let mut children = vec![element(
+            "td",
+            Some(CNN_SELECTORS.symbol_column),
+            vec![element("a", None, vec![text(symbol)])],
+        )];
+        children.extend(cells);
+        node(element("tr", None, children))
+    }
+
+    #[test]
+    fn keeps_a_halted_rows_percentage_when_it_parses() {
+        let row = row(
+            "ABC",
+            vec![
+                element("td", None, vec![text("Halted")]),
+                element(
+                    "td",
+                    Some(CNN_SELECTORS.positive_change),
+                    vec![text("+7.06%")],
+                ),
+            ],
+        );
+        let price_data = parse_row(&row, &CNN_SELECTORS, &opts()).unwrap().unwrap();
+        assert_eq!(price_data.symbol, "ABC");
+        assert!(price_data.is_halted);
+        assert_eq!(price_data.percentage.map(Percentage::value), Some(7.06));
+    }
+
+    #[test]
+    fn stores_no_percentage_for_a_row_showing_halted_in_its_place() {
+        let row = row(
+            "ABC",
+            vec![element(
+                "td",
+                Some(CNN_SELECTORS.negative_change),
+                vec![text("Halted")],
+            )],
+        );
+        let price_data = parse_row(&row, &CNN_SELECTORS, &opts()).unwrap().unwrap();
+        assert!(price_data.is_halted);
+        assert_eq!(price_data.percentage, None);
+    }
+
+    /// The error parsing `row` on its own
+    fn row_error(row: Node) -> failure::Error {
+        parse_row(&row, &CNN_SELECTORS, &opts()).unwrap_err()
+    }
+
+    #[test]
+    fn a_row_without_a_symbol_column_is_permanent() {
+        let e = row_error(node(element(
+            "tr",
+            None,
+            vec![element(
+                "td",
+                Some(CNN_SELECTORS.positive_change),
+                vec![text("+7.06%")],
+            )],
+        )));
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn a_row_without_symbol_text_is_permanent() {
+        let e = row_error(row(
+            "  ",
+            vec![element(
+                "td",
+                Some(CNN_SELECTORS.positive_change),
+                vec![text("+7.06%")],
+            )],
+        ));
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn a_missing_required_percentage_is_permanent() {
+        let e = row_error(row("ABC", vec![element("td", None, vec![text("+7.06%")])]));
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn an_unparseable_percentage_is_permanent() {
+        let e = row_error(row(
+            "ABC",
+            vec![element(
+                "td",
+                Some(CNN_SELECTORS.positive_change),
+                vec![text("n/a")],
+            )],
+        ));
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn a_missing_movers_heading_is_permanent() {
+        let opts = Opts::parse_from(["strat", "--find-movers-by-heading"]);
+        let page = node(element(
+            "body",
+            None,
+            vec![element("table", None, vec![text("Most Active")])],
+        ));
+        let e = parse_movers(&page, &CNN_SELECTORS, &opts, |_| {}).unwrap_err();
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn other_scrape_errors_are_transient() {
+        assert!(is_transient(&format_err!("timed out waiting for the page")));
+    }
+
+    #[test]
+    fn a_missing_percentage_is_an_error_only_when_required() {
+        let row = || row("ABC", vec![element("td", None, vec![text("+7.06%")])]);
+
+        let e = parse_row(&row(), &CNN_SELECTORS, &opts()).unwrap_err();
+        assert!(e.downcast_ref::<PageChanged>().is_some(), "{}", e);
+
+        let optional = Opts::parse_from(["strat", "--percentage-policy", "optional"]);
+        let price_data = parse_row(&row(), &CNN_SELECTORS, &optional)
+            .unwrap()
+            .unwrap();
+        assert_eq!(price_data.symbol, "ABC");
+        assert_eq!(price_data.percentage, None);
+    }
+
+    #[test]
+    fn a_missing_symbol_is_an_error_even_when_percentages_are_optional() {
+        let optional = Opts::parse_from(["strat", "--percentage-policy", "optional"]);
+        let row = row(
+            "",
+            vec![element(
+                "td",
+                Some(CNN_SELECTORS.positive_change),
+                vec![text("+7.06%")],
+            )],
+        );
+        let e = parse_row(&row, &CNN_SELECTORS, &optional).unwrap_err();
+        assert!(e.downcast_ref::<PageChanged>().is_some(), "{}", e);
+    }
+
+    #[test]
+    fn a_movers_container_without_its_table_body_is_an_error() {
+        let container = node(element(
+            "div",
+            None,
+            vec![element("table", None, vec![text("Gainers & Losers")])],
+        ));
+        let e = parse_movers(&container, &CNN_SELECTORS, &opts(), |_| {}).unwrap_err();
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    #[test]
+    fn a_table_body_described_without_its_rows_is_an_error() {
+        let mut body = element("tbody", None, vec![]);
+        body.as_object_mut().unwrap().remove("children");
+        let container = node(element(
+            "div",
+            None,
+            vec![element("table", None, vec![body])],
+        ));
+        let e = parse_movers(&container, &CNN_SELECTORS, &opts(), |_| {}).unwrap_err();
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    /// The index quotes, with an S&P row whose change cells hold `changes`
+    fn index_quotes(changes: Vec<Value>) -> Node {
+        node(element(
+            "div",
+            None,
+            vec![element(
+                "tr",
+                Some(CNN_SELECTORS.standard_and_poors_row),
+                vec![
+                    element("td", None, vec![text("S&P 500")]),
+                    element("td", Some(CNN_SELECTORS.index_change), changes),
+                ],
+            )],
+        ))
+    }
+
+    #[test]
+    fn parses_the_s_and_p_percentage() {
+        let quotes = index_quotes(vec![text("-12.50"), text("-0.71%")]);
+        let price_data = parse_standard_and_poors(&quotes, &CNN_SELECTORS, &opts())
+            .unwrap()
+            .unwrap();
+        assert_eq!(price_data.symbol, SP_SYMBOL);
+        assert_eq!(price_data.percentage.map(Percentage::value), Some(-0.71));
+    }
+
+    #[test]
+    fn skips_the_s_and_p_without_a_percentage() {
+        let quotes = index_quotes(vec![text("-12.50")]);
+        let price_data = parse_standard_and_poors(&quotes, &CNN_SELECTORS, &opts()).unwrap();
+        assert!(price_data.is_none());
+    }
+
+    #[test]
+    fn an_s_and_p_row_without_its_change_cells_is_an_error() {
+        let quotes = node(element(
+            "div",
+            None,
+            vec![element(
+                "tr",
+                Some(CNN_SELECTORS.standard_and_poors_row),
+                vec![element("td", None, vec![text("S&P 500")])],
+            )],
+        ));
+        let e = parse_standard_and_poors(&quotes, &CNN_SELECTORS, &opts()).unwrap_err();
+        assert!(!is_transient(&e), "{}", e);
+    }
+
+    /// An error like the one a dropped connection gives
+    fn connection_lost() -> failure::Error {
+        let e = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset");
+        postgres::error::io(e).into()
+    }
+
+    /// A stand-in for the database whose connections are numbered, each
+    /// reconnect handing out the next one
+    fn numbered_connections(limit: u32) -> Reconnecting<u32, impl FnMut() -> Fallible<u32>> {
+        let mut next = 0;
+        Reconnecting::new(0, limit, move || {
+            next += 1;
+            Ok(next)
+        })
+    }
+
+    #[test]
+    fn runs_a_step_again_on_a_fresh_connection_after_a_drop() {
+        let mut db = numbered_connections(1);
+        let mut ran_on = Vec::new();
+        let result = db.run("storing the run", |conn| {
+            ran_on.push(*conn);
+            if *conn == 0 {
+                Err(connection_lost())
+            } else {
+                Ok("stored")
+            }
+        });
+        assert_eq!(result.unwrap(), "stored");
+        assert_eq!(ran_on, vec![0, 1]);
+    }
+
+    #[test]
+    fn only_runs_the_step_that_lost_the_connection_again() {
+        let mut db = numbered_connections(1);
+        let (mut stores, mut verifies) = (0, 0);
+        db.run("storing the run", |_| {
+            stores += 1;
+            Ok(())
+        })
         .unwrap();
+        db.run("verifying the run", |conn| {
+            verifies += 1;
+            if *conn == 0 {
+                Err(connection_lost())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!((stores, verifies), (1, 2));
+    }
+
+    #[test]
+    fn gives_up_once_the_reconnects_run_out() {
+        let mut db = numbered_connections(2);
+        let mut attempts = 0;
+        let result: Fallible<()> = db.run("storing the run", |_| {
+            attempts += 1;
+            Err(connection_lost())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn doesnt_reconnect_for_other_errors() {
+        let mut db = numbered_connections(1);
+        let mut attempts = 0;
+        let result: Fallible<()> = db.run("storing the run", |_| {
+            attempts += 1;
+            Err(format_err!("duplicate key"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    fn vix() -> IndexConfig {
+        IndexConfig {
+            label: "VIX".to_string(),
+            selector: "div#vixQuote".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_a_configured_index() {
+        let quote = node(element("div", None, vec![text("+4.12%")]));
+        let price_data = parse_index(&vix(), &quote, &opts()).unwrap();
+        assert_eq!(price_data.symbol, "VIX");
+        assert_eq!(price_data.percentage.map(Percentage::value), Some(4.12));
+    }
+
+    #[test]
+    fn skips_a_configured_index_it_cant_read() {
+        let unparseable = node(element("div", None, vec![text("n/a%")]));
+        assert!(parse_index(&vix(), &unparseable, &opts()).is_none());
+        let missing = node(element("div", None, vec![text("4.12")]));
+        assert!(parse_index(&vix(), &missing, &opts()).is_none());
     }
 }
@@ -0,0 +1,312 @@
+use clap::ValueEnum;
+use core::num::ParseFloatError;
+use failure::Fail;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Which characters a source uses to separate thousands and decimals
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DecimalStyle {
+    /// "1,234.56"
+    Us,
+    /// "1.234,56"
+    Eu,
+}
+
+impl DecimalStyle {
+    /// The (thousands, decimal) separators for this style
+    fn separators(self) -> (char, char) {
+        match self {
+            DecimalStyle::Us => (',', '.'),
+            DecimalStyle::Eu => ('.', ','),
+        }
+    }
+}
+
+/// What unit a source writes its percentage changes in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PercentageUnit {
+    /// Percent, so "7.06" is 7.06%
+    Percent,
+    /// A fraction of one, so "0.0706" is 7.06%
+    Fraction,
+}
+
+/// How percentages are stored in the after_market table's percentage column
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PercentageStorage {
+    /// As a DOUBLE PRECISION percentage, e.g. 7.06
+    Float,
+    /// As an INTEGER count of basis points, e.g. 706
+    BasisPoints,
+}
+
+/// Where a mover's percentage gets its sign from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SignSource {
+    /// The rendered text, e.g. "-3.99%"
+    Text,
+    /// The cell's negChangePct/posChangePct class, so a loser is always
+    /// negative even if its text lacks a minus
+    Class,
+}
+
+/// The sign written at the front of a percentage's text, if it has one:
+/// Some(true) for a minus, Some(false) for a plus
+pub fn explicit_sign(price_change: &str) -> Option<bool> {
+    match price_change.trim().chars().next() {
+        Some('-') | Some('\u{2212}') => Some(true),
+        Some('+') => Some(false),
+        _ => None,
+    }
+}
+
+/// Percentage is a percentage change, so 7.06 means +7.06%. A price can't
+/// lose more than all of itself, so it's never below -100, and it's always a
+/// finite number.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct Percentage(f64);
+
+impl Percentage {
+    pub fn new(value: f64) -> Result<Percentage, PercentageError> {
+        if value.is_finite() && value >= -100.0 {
+            Ok(Percentage(value))
+        } else {
+            Err(PercentageError::OutOfRange(value))
+        }
+    }
+
+    /// Strip away the % char and any thousands separators so "+7.06%"
+    /// becomes 7.06, or with `DecimalStyle::Eu` "1.234,56%" becomes 1234.56.
+    /// A thousands separator anywhere but between groups of three digits is
+    /// an error, so "7,06%" doesn't become 706 when read as `DecimalStyle::Us`.
+    /// With `PercentageUnit::Fraction` the number is scaled up to percent, so
+    /// "0.0706" becomes 7.06 too.
+    pub fn parse(
+        price_change: &str,
+        style: DecimalStyle,
+        unit: PercentageUnit,
+    ) -> Result<Percentage, PercentageError> {
+        let (thousands, decimal) = style.separators();
+        let number = strip_thousands(
+            price_change.trim().trim_end_matches('%'),
+            thousands,
+            decimal,
+        )
+        .ok_or_else(|| PercentageError::Grouping(price_change.to_string()))?;
+        let number: String = number
+            .chars()
+            .map(|c| match c {
+                c if c == decimal => '.',
+                // some pages render a typographic minus sign instead of a hyphen
+                '\u{2212}' => '-',
+                c => c,
+            })
+            .collect();
+
+        match number.parse::<f64>() {
+            Ok(value) => match unit {
+                PercentageUnit::Percent => Percentage::new(value),
+                PercentageUnit::Fraction => Percentage::new(value * 100.0),
+            },
+            Err(e) => Err(PercentageError::Parse(price_change.to_string(), e)),
+        }
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+
+    pub fn abs(self) -> Percentage {
+        Percentage(self.0.abs())
+    }
+
+    pub fn is_gain(self) -> bool {
+        self.0 > 0.0
+    }
+
+    pub fn is_loss(self) -> bool {
+        self.0 < 0.0
+    }
+
+    /// The percentage in whole basis points (hundredths of a percent), so
+    /// 7.06 becomes 706. Fractions of a basis point round half-to-even.
+    pub fn basis_points(self) -> i32 {
+        round_half_to_even(self.0 * 100.0, 0) as i32
+    }
+
+    /// Round to `precision` decimal places, halves to even
+    pub fn rounded(self, precision: u32) -> Percentage {
+        Percentage(round_half_to_even(self.0, precision))
+    }
+}
+
+/// Parses the way `Percentage::parse` does with `DecimalStyle::Us` and
+/// `PercentageUnit::Percent`
+impl FromStr for Percentage {
+    type Err = PercentageError;
+
+    fn from_str(s: &str) -> Result<Percentage, PercentageError> {
+        Percentage::parse(s, DecimalStyle::Us, PercentageUnit::Percent)
+    }
+}
+
+/// Drop the thousands separators from `number`. They may only come between
+/// groups of three digits before the decimal separator, so None if there's
+/// one anywhere else.
+fn strip_thousands(number: &str, thousands: char, decimal: char) -> Option<String> {
+    let digits = number.trim_start_matches(['+', '-', '\u{2212}']);
+    let sign = &number[..number.len() - digits.len()];
+    let (whole, fraction) = match digits.find(decimal) {
+        Some(i) => digits.split_at(i),
+        None => (digits, ""),
+    };
+    if fraction.contains(thousands) {
+        return None;
+    }
+
+    if whole.contains(thousands) {
+        let is_digits = |group: &str| group.chars().all(|c| c.is_ascii_digit());
+        let mut groups = whole.split(thousands);
+        let lead = groups.next().unwrap_or_default();
+        let grouped = (1..=3).contains(&lead.len())
+            && is_digits(lead)
+            && groups.all(|group| group.len() == 3 && is_digits(group));
+        if !grouped {
+            return None;
+        }
+    }
+
+    Some(format!(
+        "{}{}{}",
+        sign,
+        whole.replace(thousands, ""),
+        fraction
+    ))
+}
+
+impl fmt::Display for Percentage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum PercentageError {
+    /// The text wasn't a number once the % and separators were stripped
+    Parse(String, ParseFloatError),
+    /// The text had a thousands separator somewhere other than between
+    /// groups of three digits
+    Grouping(String),
+    /// The number can't be a percentage change
+    OutOfRange(f64),
+}
+
+impl fmt::Display for PercentageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PercentageError::Parse(text, e) => write!(f, "couldn't parse {:?}: {}", text, e),
+            PercentageError::Grouping(text) => {
+                write!(
+                    f,
+                    "couldn't parse {:?}: misplaced thousands separator",
+                    text
+                )
+            }
+            PercentageError::OutOfRange(value) => {
+                write!(f, "{} isn't a possible percentage change", value)
+            }
+        }
+    }
+}
+
+impl Fail for PercentageError {}
+
+/// Convert whole basis points back to a percentage, so 706 becomes 7.06
+pub fn from_basis_points(basis_points: i32) -> f64 {
+    f64::from(basis_points) / 100.0
+}
+
+/// Round `value` to `precision` decimal places, sending exact halves to the
+/// even neighbour (banker's rounding) so rounding many values doesn't bias
+/// them upward the way `f64::round` would
+fn round_half_to_even(value: f64, precision: u32) -> f64 {
+    let scale = 10f64.powi(precision as i32);
+    let scaled = value * scale;
+
+    let mut rounded = scaled.round();
+    if (scaled - scaled.trunc()).abs() == 0.5 && rounded % 2.0 != 0.0 {
+        // `round` went away from zero onto an odd number, so step back
+        rounded -= scaled.signum();
+    }
+
+    rounded / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str, style: DecimalStyle) -> Result<f64, PercentageError> {
+        Percentage::parse(s, style, PercentageUnit::Percent).map(Percentage::value)
+    }
+
+    #[test]
+    fn parses_thousands_separators_in_either_style() {
+        assert_eq!(parse("1.234,56%", DecimalStyle::Eu).unwrap(), 1234.56);
+        assert_eq!(parse("1,234.56%", DecimalStyle::Us).unwrap(), 1234.56);
+        assert_eq!(parse("+12,345,678%", DecimalStyle::Us).unwrap(), 12345678.0);
+        assert_eq!(parse("\u{2212}3,99%", DecimalStyle::Eu).unwrap(), -3.99);
+        assert_eq!(parse("+7.06%", DecimalStyle::Us).unwrap(), 7.06);
+    }
+
+    #[test]
+    fn rejects_misplaced_thousands_separators() {
+        for (text, style) in [
+            // an EU decimal read as US
+            ("7,06%", DecimalStyle::Us),
+            ("1.234,56%", DecimalStyle::Us),
+            ("1,234.56%", DecimalStyle::Eu),
+            ("12,34.5%", DecimalStyle::Us),
+            ("1234,567%", DecimalStyle::Us),
+            (",123%", DecimalStyle::Us),
+            ("1,234,%", DecimalStyle::Us),
+        ]
+        .iter()
+        {
+            match parse(text, *style) {
+                Err(PercentageError::Grouping(_)) => {}
+                other => panic!("{:?} as {:?} gave {:?}", text, style, other),
+            }
+        }
+    }
+
+    #[test]
+    fn scales_fractions_up_to_percent() {
+        let fraction = |s: &str| {
+            Percentage::parse(s, DecimalStyle::Us, PercentageUnit::Fraction)
+                .unwrap()
+                .value()
+        };
+        assert!((fraction("0.0706") - 7.06).abs() < 1e-9);
+        assert!((fraction("-0.0399") + 3.99).abs() < 1e-9);
+        assert_eq!(fraction("1"), 100.0);
+        assert_eq!(
+            Percentage::parse("0.0706", DecimalStyle::Us, PercentageUnit::Fraction)
+                .unwrap()
+                .basis_points(),
+            706
+        );
+    }
+
+    #[test]
+    fn checks_a_fractions_range_once_scaled() {
+        assert!(Percentage::parse("-1", DecimalStyle::Us, PercentageUnit::Fraction).is_ok());
+        match Percentage::parse("-1.5", DecimalStyle::Us, PercentageUnit::Fraction) {
+            Err(PercentageError::OutOfRange(value)) => assert_eq!(value, -150.0),
+            other => panic!("-1.5 as a fraction gave {:?}", other),
+        }
+    }
+}
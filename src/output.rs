@@ -0,0 +1,283 @@
+use chrono::{DateTime, FixedOffset, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+use crate::buckets::Bucket;
+use crate::cli::{CsvColumn, CsvField, IndexConfig, OutputFormat};
+use crate::numbers::Percentage;
+use crate::AfterMarketPriceData;
+
+/// A row with its date shifted into the --display-tz zone, for printing
+#[derive(Debug, Serialize)]
+struct DisplayRow<'a> {
+    symbol: &'a str,
+    percentage: Option<Percentage>,
+    date: DateTime<FixedOffset>,
+    is_halted: bool,
+    bucket: Option<Bucket>,
+}
+
+/// Print the data to stdout in the requested format, with dates shown in
+/// `display_tz` if one is given
+pub fn write_output(
+    after_market_data: &[AfterMarketPriceData],
+    format: OutputFormat,
+    display_tz: Option<Tz>,
+    csv_columns: &[CsvColumn],
+    indices: &[IndexConfig],
+) -> Result<(), failure::Error> {
+    let stdout = io::stdout();
+    write_to(
+        &mut stdout.lock(),
+        after_market_data,
+        format,
+        display_tz,
+        csv_columns,
+        indices,
+    )
+}
+
+/// Write the data to the file at `path` in the requested format. We write to
+/// a temporary file next to it and rename it into place once it's complete,
+/// so anyone watching `path` never sees a half-written file.
+pub fn write_output_file(
+    path: &Path,
+    after_market_data: &[AfterMarketPriceData],
+    format: OutputFormat,
+    display_tz: Option<Tz>,
+    csv_columns: &[CsvColumn],
+    indices: &[IndexConfig],
+) -> Result<(), failure::Error> {
+    write_atomically(path, |file| {
+        write_to(
+            file,
+            after_market_data,
+            format,
+            display_tz,
+            csv_columns,
+            indices,
+        )
+    })
+}
+
+/// Replace the file at `path` with whatever `write` puts in a temporary file
+/// beside it, so anyone reading `path` only ever sees a complete file
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<(), failure::Error>
+where
+    F: FnOnce(&mut File) -> Result<(), failure::Error>,
+{
+    // the temporary file has to be on the same filesystem for the rename to
+    // be atomic, so put it in the same directory
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    write(file.as_file_mut())?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+
+    Ok(())
+}
+
+fn write_to<W: Write>(
+    out: &mut W,
+    after_market_data: &[AfterMarketPriceData],
+    format: OutputFormat,
+    display_tz: Option<Tz>,
+    csv_columns: &[CsvColumn],
+    indices: &[IndexConfig],
+) -> Result<(), failure::Error> {
+    match (format, display_tz) {
+        (OutputFormat::Debug, None) => writeln!(out, "{:?}", after_market_data)?,
+        (OutputFormat::Debug, Some(tz)) => {
+            writeln!(out, "{:?}", display_rows(after_market_data, tz))?
+        }
+        (OutputFormat::Jsonl, None) => {
+            for d in after_market_data.iter() {
+                write_jsonl_record(out, d)?;
+            }
+        }
+        (OutputFormat::Jsonl, Some(tz)) => {
+            for r in display_rows(after_market_data, tz).iter() {
+                write_jsonl_record(out, r)?;
+            }
+        }
+        (OutputFormat::Markdown, _) => {
+            write!(out, "{}", render_markdown(after_market_data, indices))?
+        }
+        (OutputFormat::Csv, _) => {
+            let columns = if csv_columns.is_empty() {
+                DEFAULT_CSV_COLUMNS
+                    .iter()
+                    .map(|field| CsvColumn {
+                        field: *field,
+                        header: field_name(*field).to_string(),
+                    })
+                    .collect()
+            } else {
+                csv_columns.to_vec()
+            };
+
+            let headers: Vec<String> = columns.iter().map(|c| csv_field(&c.header)).collect();
+            writeln!(out, "{}", headers.join(","))?;
+            for d in after_market_data.iter() {
+                let cells: Vec<String> = columns
+                    .iter()
+                    .map(|c| csv_cell(d, c.field, display_tz))
+                    .collect();
+                writeln!(out, "{}", cells.join(","))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The columns --output csv writes when no --csv-column is given
+const DEFAULT_CSV_COLUMNS: &[CsvField] = &[
+    CsvField::Symbol,
+    CsvField::Percentage,
+    CsvField::Date,
+    CsvField::IsHalted,
+    CsvField::Bucket,
+];
+
+/// The name a field goes by in --csv-column and the default CSV header
+fn field_name(field: CsvField) -> &'static str {
+    match field {
+        CsvField::Symbol => "symbol",
+        CsvField::Percentage => "percentage",
+        CsvField::Date => "date",
+        CsvField::IsHalted => "is_halted",
+        CsvField::Bucket => "bucket",
+    }
+}
+
+fn csv_cell(d: &AfterMarketPriceData, field: CsvField, display_tz: Option<Tz>) -> String {
+    match field {
+        CsvField::Symbol => csv_field(&d.symbol),
+        CsvField::Percentage => d.percentage.map(|p| p.to_string()).unwrap_or_default(),
+        CsvField::Date => match display_tz {
+            Some(tz) => in_tz(&d.date, tz).to_rfc3339(),
+            None => d.date.to_rfc3339(),
+        },
+        CsvField::IsHalted => d.is_halted.to_string(),
+        CsvField::Bucket => d.bucket.map_or("", Bucket::label).to_string(),
+    }
+}
+
+fn display_rows(after_market_data: &[AfterMarketPriceData], tz: Tz) -> Vec<DisplayRow<'_>> {
+    after_market_data
+        .iter()
+        .map(|d| DisplayRow {
+            symbol: &d.symbol,
+            percentage: d.percentage,
+            date: in_tz(&d.date, tz),
+            is_halted: d.is_halted,
+            bucket: d.bucket,
+        })
+        .collect()
+}
+
+/// The same instant as `date`, with the UTC offset `tz` had at the time
+fn in_tz(date: &DateTime<Utc>, tz: Tz) -> DateTime<FixedOffset> {
+    let offset = tz.offset_from_utc_datetime(&date.naive_utc()).fix();
+    DateTime::from_utc(date.naive_utc(), offset)
+}
+
+/// Render the data as GitHub-flavored Markdown, for pasting into a chat or a
+/// wiki: the gainers biggest first, then the losers biggest first, then the
+/// S&P and `indices`. Halted tickers the page gave no percentage for are
+/// listed last.
+pub fn render_markdown(
+    after_market_data: &[AfterMarketPriceData],
+    indices: &[IndexConfig],
+) -> String {
+    let (indices, movers): (Vec<_>, Vec<_>) =
+        after_market_data.iter().partition(|d| d.is_index(indices));
+
+    let mut gainers: Vec<_> = movers
+        .iter()
+        .copied()
+        .filter(|d| d.percentage.is_some_and(Percentage::is_gain))
+        .collect();
+    gainers.sort_by(|a, b| b.percentage.partial_cmp(&a.percentage).unwrap());
+    let mut losers: Vec<_> = movers
+        .iter()
+        .copied()
+        .filter(|d| d.percentage.is_some_and(Percentage::is_loss))
+        .collect();
+    losers.sort_by(|a, b| a.percentage.partial_cmp(&b.percentage).unwrap());
+
+    let mut markdown = String::new();
+    markdown_table(&mut markdown, "Gainers", &gainers);
+    markdown_table(&mut markdown, "Losers", &losers);
+    markdown_table(&mut markdown, "Indices", &indices);
+
+    let halted: Vec<_> = movers
+        .iter()
+        .filter(|d| d.percentage.is_none())
+        .map(|d| markdown_cell(&d.symbol))
+        .collect();
+    if !halted.is_empty() {
+        markdown.push_str(&format!("Halted: {}\n", halted.join(", ")));
+    }
+
+    markdown
+}
+
+fn markdown_table(markdown: &mut String, title: &str, rows: &[&AfterMarketPriceData]) {
+    markdown.push_str(&format!("### {}\n\n", title));
+    if rows.is_empty() {
+        markdown.push_str("None\n\n");
+        return;
+    }
+
+    markdown.push_str("| Symbol | Change |\n| :--- | ---: |\n");
+    for d in rows.iter() {
+        let change = match d.percentage {
+            Some(p) => format!("{:+}%", p.value()),
+            None => String::new(),
+        };
+        let halted = if d.is_halted { " (halted)" } else { "" };
+        markdown.push_str(&format!(
+            "| {} | {}{} |\n",
+            markdown_cell(&d.symbol),
+            change,
+            halted
+        ));
+    }
+    markdown.push('\n');
+}
+
+/// Escape the pipes that would otherwise split a Markdown table cell
+pub fn markdown_cell(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+/// Write a single record as one line of JSON, flushing right away so a
+/// downstream consumer sees it without waiting for the rest of the batch
+pub fn write_jsonl_record<W: Write, T: Serialize>(
+    out: &mut W,
+    record: &T,
+) -> Result<(), failure::Error> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Quote a CSV field if it contains anything that would otherwise break the
+/// row apart
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
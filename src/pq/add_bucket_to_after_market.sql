@@ -0,0 +1,5 @@
+-- Each row's percentage bucket (big_loss, loss, flat, gain or big_gain), set
+-- from --bucket-thresholds. Run this against an existing after_market table
+-- created before buckets were stored.
+ALTER TABLE after_market ADD COLUMN IF NOT EXISTS bucket VARCHAR(10);
+CREATE INDEX ON after_market (bucket);
@@ -0,0 +1,5 @@
+-- Halted tickers are stored with is_halted set, and with a NULL percentage
+-- when the page shows the halt instead of one. Run this against an existing
+-- after_market table created before halted tickers were recorded.
+ALTER TABLE after_market ALTER COLUMN percentage DROP NOT NULL;
+ALTER TABLE after_market ADD COLUMN IF NOT EXISTS is_halted BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- The id of the run that stored each row, as logged when the run started.
+-- Run this against an existing after_market table created before runs were
+-- tagged; rows stored before then are left NULL.
+ALTER TABLE after_market ADD COLUMN IF NOT EXISTS run_id UUID;
+CREATE INDEX ON after_market (run_id);
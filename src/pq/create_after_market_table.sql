@@ -1,10 +1,16 @@
+-- The output of `strat schema`, which the tests check it against
 CREATE TABLE IF NOT EXISTS after_market(
    symbol           VARCHAR(10)         NOT NULL,
-   percentage       DOUBLE PRECISION    NOT NULL,
+   percentage       DOUBLE PRECISION,
    date             TIMESTAMP WITH TIME ZONE,
+   is_halted        BOOLEAN             NOT NULL DEFAULT FALSE,
+   bucket           VARCHAR(10),
+   run_id           UUID,
    PRIMARY KEY      (symbol, date)
 );
 
 CREATE INDEX ON after_market (symbol);
 CREATE INDEX ON after_market (percentage);
 CREATE INDEX ON after_market (date);
+CREATE INDEX ON after_market (bucket);
+CREATE INDEX ON after_market (run_id);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS run_summaries(
+   date             TIMESTAMP WITH TIME ZONE    NOT NULL,
+   gainers          INTEGER                     NOT NULL,
+   losers           INTEGER                     NOT NULL,
+   ratio            DOUBLE PRECISION,
+   PRIMARY KEY      (date)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS snapshots(
+   date             TIMESTAMP WITH TIME ZONE    NOT NULL,
+   data             JSONB                       NOT NULL,
+   PRIMARY KEY      (date)
+);
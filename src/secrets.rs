@@ -0,0 +1,21 @@
+use failure::format_err;
+use std::env;
+use std::fs;
+
+/// Look up the secret `name`, first as an env var and then, if that is unset,
+/// as the contents of the file named by the `<name>_FILE` env var (the way
+/// Docker and Kubernetes mount secrets). Trailing whitespace and newlines are
+/// trimmed from the file's contents.
+pub fn read_secret(name: &str) -> Result<String, failure::Error> {
+    if let Ok(value) = env::var(name) {
+        return Ok(value);
+    }
+
+    let file_var = format!("{}_FILE", name);
+    let path = env::var(&file_var)
+        .map_err(|_| format_err!("neither env var {} nor {} is set", name, file_var))?;
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format_err!("couldn't read {} from {:?}: {}", file_var, path, e))?;
+
+    Ok(contents.trim_end().to_string())
+}
@@ -0,0 +1,49 @@
+/// SelectorSet holds every CSS selector, class name, and tag name we use to
+/// find data in the page, so they're spelled out once instead of as string
+/// literals scattered through the extractors
+#[derive(Debug)]
+pub struct SelectorSet {
+    /// the page's body, searched when finding the movers table by its heading
+    pub page_body: &'static str,
+    /// the element wrapping the afterhours Gainers and Losers table
+    pub movers_container: &'static str,
+    /// the tag of the movers table's body, whose children are its rows
+    pub movers_body: &'static str,
+    /// the text of the movers table's header row
+    pub movers_header: &'static str,
+    /// the class of the column holding a mover's ticker symbol
+    pub symbol_column: &'static str,
+    /// the class of the column holding a losing mover's change
+    pub negative_change: &'static str,
+    /// the class of the column holding a gaining mover's change
+    pub positive_change: &'static str,
+    /// the text a mover's row shows when its trading is halted
+    pub halted_marker: &'static str,
+    /// the element wrapping the index futures quotes
+    pub index_container: &'static str,
+    /// the class of the S&P's row among the index quotes
+    pub standard_and_poors_row: &'static str,
+    /// the class of the cells holding an index quote's changes
+    pub index_change: &'static str,
+}
+
+/// The selectors matching CNN's after-hours movers page
+pub const CNN_SELECTORS: SelectorSet = SelectorSet {
+    page_body: "body",
+    movers_container: "div#wsod_marketMoversContainer",
+    movers_body: "TBODY",
+    movers_header: "Gainers & Losers",
+    symbol_column: "wsod_firstCol",
+    negative_change: "negChangePct",
+    positive_change: "posChangePct",
+    halted_marker: "Halted",
+    index_container: "div#premkContent1",
+    standard_and_poors_row: "wsod_futureQuote wsod_futureQuoteFirst",
+    index_change: "wsod_bold wsod_aRight",
+};
+
+/// The tag name headless_chrome gives tables
+pub const TABLE_TAG: &str = "TABLE";
+
+/// The tag name headless_chrome gives text nodes
+pub const TEXT_NODE: &str = "#text";
@@ -0,0 +1,168 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::cli::IndexConfig;
+use crate::AfterMarketPriceData;
+
+/// RunStatus is what --status-file says about the last scrape, for
+/// monitoring that just wants to know whether it worked
+#[derive(Debug, Serialize)]
+pub struct RunStatus {
+    pub ok: bool,
+    pub rows: usize,
+    pub duration_ms: u64,
+    pub run_id: String,
+    pub date: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+impl RunStatus {
+    /// The status of a run started at `date` that took `duration` and ended
+    /// with `result`, the number of rows it scraped or why it failed
+    pub fn new(
+        run_id: String,
+        date: DateTime<Utc>,
+        duration: Duration,
+        result: &Result<usize, failure::Error>,
+    ) -> RunStatus {
+        let (rows, error) = match result {
+            Ok(rows) => (*rows, None),
+            Err(e) => (0, Some(e.to_string())),
+        };
+        RunStatus {
+            ok: result.is_ok(),
+            rows,
+            duration_ms: duration.as_millis() as u64,
+            run_id,
+            date,
+            error,
+        }
+    }
+}
+
+/// RunSummary is a one-number breadth read of a single scrape: how many of
+/// the movers we captured went up versus down
+#[derive(Debug)]
+pub struct RunSummary {
+    pub date: DateTime<Utc>,
+    pub gainers: usize,
+    pub losers: usize,
+}
+
+impl RunSummary {
+    /// Count the gainers and losers in `after_market_data`, ignoring the S&P
+    /// and `indices` rows since they aren't movers themselves
+    pub fn new(
+        date: DateTime<Utc>,
+        after_market_data: &[AfterMarketPriceData],
+        indices: &[IndexConfig],
+    ) -> RunSummary {
+        let movers = after_market_data.iter().filter(|d| !d.is_index(indices));
+        let (mut gainers, mut losers) = (0, 0);
+        for d in movers {
+            match d.percentage {
+                Some(p) if p.is_gain() => gainers += 1,
+                Some(p) if p.is_loss() => losers += 1,
+                _ => {}
+            }
+        }
+
+        RunSummary {
+            date,
+            gainers,
+            losers,
+        }
+    }
+
+    /// The number of gainers per loser, or `None` if there were no losers
+    pub fn ratio(&self) -> Option<f64> {
+        if self.losers == 0 {
+            None
+        } else {
+            Some(self.gainers as f64 / self.losers as f64)
+        }
+    }
+}
+
+/// A cheap tripwire for reading the wrong column: if more than `fraction` of
+/// the movers' percentages are exactly zero, or all the same value, describe
+/// what looks wrong
+pub fn misparse_warning(
+    after_market_data: &[AfterMarketPriceData],
+    fraction: f64,
+    indices: &[IndexConfig],
+) -> Option<String> {
+    let percentages: Vec<f64> = after_market_data
+        .iter()
+        .filter(|d| !d.is_index(indices))
+        .filter_map(|d| d.percentage.map(|p| p.value()))
+        .collect();
+    // one or two rows agreeing doesn't tell us anything
+    if percentages.len() < 3 {
+        return None;
+    }
+    let share = |count: usize| count as f64 / percentages.len() as f64;
+
+    let zeros = percentages.iter().filter(|p| **p == 0.0).count();
+    if share(zeros) > fraction {
+        return Some(format!(
+            "{} of {} percentages are exactly 0",
+            zeros,
+            percentages.len()
+        ));
+    }
+
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for p in percentages.iter() {
+        *counts.entry(p.to_bits()).or_default() += 1;
+    }
+    let (bits, most) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+    if share(most) > fraction {
+        return Some(format!(
+            "{} of {} percentages are {}",
+            most,
+            percentages.len(),
+            f64::from_bits(bits)
+        ));
+    }
+
+    None
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} gainers, {} losers", self.gainers, self.losers)?;
+        match self.ratio() {
+            Some(ratio) => write!(f, " (ratio {:.2})", ratio),
+            None => write!(f, " (ratio n/a)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_row;
+
+    #[test]
+    fn leaves_the_s_and_p_and_configured_indices_out_of_the_movers() {
+        let indices = vec![IndexConfig {
+            label: "RUT".to_string(),
+            selector: "div#rut".to_string(),
+        }];
+        let after_market_data = vec![
+            test_row("ABC", Some(7.06)),
+            test_row("XYZ", Some(-3.99)),
+            test_row("S&P", Some(0.5)),
+            test_row("RUT", Some(-1.2)),
+            // only an index once it's configured as one
+            test_row("VIX", Some(4.0)),
+        ];
+
+        let summary = RunSummary::new(Utc::now(), &after_market_data, &indices);
+        assert_eq!((summary.gainers, summary.losers), (2, 1));
+    }
+}
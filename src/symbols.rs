@@ -0,0 +1,40 @@
+/// Put a ticker symbol into the one canonical form we store, so the same
+/// company scraped from different sources dedups properly: surrounding
+/// whitespace is trimmed, letters are uppercased, and then any known alias
+/// (like "BRK-B" for "BRK.B") is swapped for the symbol it stands for
+pub fn normalize_symbol(raw: &str, aliases: &[(String, String)]) -> String {
+    let symbol = raw.trim().to_uppercase();
+    match aliases.iter().find(|(alias, _)| *alias == symbol) {
+        Some((_, canonical)) => canonical.clone(),
+        None => symbol,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases() -> Vec<(String, String)> {
+        vec![("BRK-B".to_string(), "BRK.B".to_string())]
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        assert_eq!(normalize_symbol("  ABC\n", &[]), "ABC");
+    }
+
+    #[test]
+    fn uppercases_letters() {
+        assert_eq!(normalize_symbol("abc", &[]), "ABC");
+        assert_eq!(normalize_symbol("Brk.b", &[]), "BRK.B");
+    }
+
+    #[test]
+    fn swaps_an_alias_for_its_symbol() {
+        assert_eq!(normalize_symbol("BRK-B", &aliases()), "BRK.B");
+        // the alias matches once trimmed and uppercased
+        assert_eq!(normalize_symbol(" brk-b ", &aliases()), "BRK.B");
+        assert_eq!(normalize_symbol("BRK.B", &aliases()), "BRK.B");
+        assert_eq!(normalize_symbol("BRK-A", &aliases()), "BRK-A");
+    }
+}